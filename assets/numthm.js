@@ -0,0 +1,20 @@
+// Highlights the environment targeted by a reference (mdbook-numthm),
+// for themes where the `:target` pseudo-class is unreliable.
+(function () {
+  function highlight() {
+    var label = decodeURIComponent(window.location.hash.slice(1));
+    if (!label) {
+      return;
+    }
+    document.querySelectorAll("a.numthm-target").forEach(function (a) {
+      a.classList.remove("numthm-highlight");
+      if (a.name === label || a.id === label) {
+        // force the animation to restart
+        void a.offsetWidth;
+        a.classList.add("numthm-highlight");
+      }
+    });
+  }
+  window.addEventListener("hashchange", highlight);
+  window.addEventListener("DOMContentLoaded", highlight);
+})();
@@ -1,425 +1,8545 @@
 //! An [mdBook](https://github.com/rust-lang/mdBook) preprocessor for automatically numbering theorems, lemmas, etc.
 
+use lazy_static::lazy_static;
 use log::warn;
-use mdbook::book::{Book, BookItem};
-use mdbook::errors::Result;
+use mdbook::book::{Book, BookItem, SectionNumber};
+use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::{Config, MDBook};
 use pathdiff::diff_paths;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 /// The preprocessor name.
 const NAME: &str = "numthm";
 
+/// The comment starting a region left untouched by the preprocessor.
+const SKIP_START: &str = "<!-- numthm:skip-start -->";
+
+/// The comment ending a region left untouched by the preprocessor.
+const SKIP_END: &str = "<!-- numthm:skip-end -->";
+
+/// The name of the directive replaced by the open-problems registry.
+const REGISTRY: &str = "open-problems";
+
+/// The statuses which can be given to environments, with the corresponding registry headings.
+const STATUSES: [(&str, &str); 3] = [
+    ("open", "Open"),
+    ("partial", "Partially solved"),
+    ("solved", "Solved"),
+];
+
+/// The opening delimiter of the page reference placeholder emitted when `print_pagerefs` is set.
+const PAGEREF_OPEN: &str = "⟨pageref:";
+
+/// The closing delimiter of the page reference placeholder emitted when `print_pagerefs` is set.
+const PAGEREF_CLOSE: &str = "⟩";
+
+/// The comment starting a region produced by the preprocessor, which is left untouched if processed again.
+const PROCESSED_START: &str = "<!-- numthm:processed-start -->";
+
+/// The comment ending a region produced by the preprocessor.
+const PROCESSED_END: &str = "<!-- numthm:processed-end -->";
+
+/// The name of the directives delimiting a group of sub-equations, e.g. `{{subeq-begin}}`.
+const SUBEQUATIONS: &str = "subeq";
+
+/// The counter marking an open group of sub-equations, which ends with `SUBNUMBER` so that it is neither continued
+/// in the next chapter nor exported.
+const SUBEQ_OPEN: &str = "subeq+";
+
+/// The name of the directive marking the start of appendices.
+const APPENDIX: &str = "appendix";
+
+/// The delimiters of the placeholder of the total number of environments sharing a counter in a chapter,
+/// emitted in headers by the first pass when `show_total` is set and filled in by the second pass.
+const TOTAL_OPEN: &str = "⟨numthm-total:";
+const TOTAL_CLOSE: &str = "⟩";
+
+/// The suffix of the key of sub-numbered environments, e.g. `{{thm+}}`, also appended to counter names
+/// to count the environments attached to the last numbered one.
+const SUBNUMBER: &str = "+";
+
+/// The suffix appended to environment keys to record, among counters, the counter value of the last occurrence
+/// of environments within which other environments are numbered.
+const PARENT: &str = "^";
+
+/// The name of the directive setting counters, e.g. `{{set: thm=4}}`.
+const SET: &str = "set";
+
+/// The types of references, e.g. `{{tref: label}}`.
+const REFTYPES: [&str; 7] = ["ref", "tref", "qtref", "sref", "pref", "eqref", "jsref"];
+
+/// The default markup of equation tags, right-aligned by the `numthm-eq-tag` CSS rule.
+const EQ_TAG: &str = "<span class=\"numthm-eq-tag\">({num})</span>";
+
+/// The default markup of equation tags for renderers other than HTML.
+const EQ_TAG_PLAIN: &str = "({num})";
+
+/// The prefix of the anchors of the entries of the list of references in citation mode.
+const CITATION_ANCHOR: &str = "numthm-cite-";
+
+/// The name of the directive starting a proof block, ended by `{{proof-end}}` or `{{end:proof}}`.
+const PROOF: &str = "proof";
+
+/// The marker appended to proofs detected by `detect_proofs`.
+const QED: &str = "∎";
+
+/// The CSS class given to anchors when `highlight_target` is set.
+const TARGET_CLASS: &str = "numthm-target";
+
+/// The stylesheet highlighting targeted environments.
+pub const CSS: &str = include_str!("../assets/numthm.css");
+
+/// The script highlighting targeted environments for themes where `:target` is unreliable.
+pub const JS: &str = include_str!("../assets/numthm.js");
+
+// see https://regex101.com/ for an explanation of the regexes
+lazy_static! {
+    /// The regex matching numbers, used to sort lists of environments by number.
+    static ref NUMBER_RE: Regex = Regex::new(r"\d+").unwrap();
+    /// The regex matching blank lines (or the end of the text) ending quoted statements.
+    static ref BLANK_LINE_RE: Regex = Regex::new(r"\n[ \t]*(\n|$)").unwrap();
+    /// The regex matching total placeholders.
+    static ref TOTAL_RE: Regex = Regex::new(&format!(
+        "{}(?P<counter>[^{TOTAL_CLOSE}]*){TOTAL_CLOSE}",
+        regex::escape(TOTAL_OPEN)
+    ))
+    .unwrap();
+    /// The regex matching ATX headings of levels 2 to 6.
+    static ref HEADING_RE: Regex = Regex::new(r"(?m)^ {0,3}(#{2,6})(?:[ \t]|$)").unwrap();
+    /// The regex matching attributes `key=value` or `key="value"`.
+    static ref ATTRIBUTE_RE: Regex =
+        Regex::new(r#"(?P<key>[\w-]+)\s*=\s*("(?P<quoted>[^"]*)"|(?P<value>\S+))"#).unwrap();
+}
+
 /// An environment handled by the preprocessor.
+#[derive(Clone)]
 struct Env {
     /// The key to match to detect the environment, e.g. "thm".
     key: String,
     /// The name to display in the header, e.g. "Theorem".
     name: String,
+    /// The plural of the name if it is irregular, e.g. "Hypotheses" (defaults to the name followed by "s").
+    plural: Option<String>,
     /// The markdown emphasis delimiter to apply to the header, e.g. "**" for bold.
     emph: String,
+    /// An optional template replacing the default "Name N" shape of the numbered name, e.g. "(H{n})";
+    /// placeholders `{name}`, `{prefix}`, and `{n}` are replaced by the name, the prefix, and the counter.
+    template: Option<String>,
+    /// An optional template replacing the default "Name N (Title)." shape of the header, e.g. "{num}. {name}";
+    /// placeholders `{name}`, `{num}`, and `{title}` are replaced by the name, the number, and the title.
+    header_template: Option<String>,
+    /// When the counter of the environment is reset, if it differs from the policy of the preprocessor.
+    reset: Option<Reset>,
+    /// An optional format applied to the number (prefix included), e.g. "({n})" for "Theorem (1.2.1)".
+    counter_format: Option<String>,
+    /// The rendering style of the environment.
+    style: Style,
+    /// How the counter of the environment is written, if it differs from the style of the preprocessor.
+    numbering_style: Option<NumberingStyle>,
+    /// The first value of the counter of the environment (after each reset), e.g. 17 for "Theorem 17".
+    start: usize,
+    /// Whether the header must display the total number of environments sharing the counter,
+    /// e.g. "Exercise 3 of 12", in the chapter (or until the counter is reset).
+    show_total: bool,
+    /// The key of the environment within which the environment is numbered, e.g. "thm" for
+    /// "Corollary 2.3.1" following "Theorem 2.3", the counter restarting with each occurrence of the parent.
+    within: Option<String>,
+    /// Whether the rest of the line following the directive is captured into the emphasized header,
+    /// e.g. for one-line remarks (plain style only).
+    capture_line: bool,
+    /// The minimal width of the counter of the environment written with Arabic numerals,
+    /// padded with zeros, e.g. 3 for "Theorem 007" (0 for no padding).
+    pad_width: usize,
+    /// The name of the counter of the environment if it differs from the key,
+    /// the counter being shared by all environments with the same counter name.
+    counter: Option<String>,
+    /// The regex matching the directives of the environment, compiled once (see `Env::compile_regex`).
+    regex: Regex,
+}
+
+/// The rendering style of an environment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Style {
+    /// Only the header is rendered, followed by the statement as written.
+    Plain,
+    /// The header and the statement (up to the next blank line or to the end marker `{{key-end}}`)
+    /// are rendered as a blockquote.
+    Quote,
+}
+
+impl Style {
+    /// Returns the configuration value corresponding to the style.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Style::Plain => "plain",
+            Style::Quote => "quote",
+        }
+    }
+}
+
+impl Env {
+    /// Creates an environment with the default numbering template and the default reset policy.
+    fn new(key: &str, name: &str, emph: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            name: name.to_string(),
+            plural: None,
+            emph: emph.to_string(),
+            template: None,
+            header_template: None,
+            reset: None,
+            counter_format: None,
+            style: Style::Plain,
+            numbering_style: None,
+            start: 1,
+            pad_width: 0,
+            show_total: false,
+            capture_line: false,
+            within: None,
+            counter: None,
+            regex: Self::compile_regex(key, "", DirectiveSyntax::Brackets),
+        }
+    }
+
+    /// Parses an environment from a TOML table such as
+    /// `{ key = "hyp", name = "Hypothesis", emph = "**", template = "(H{n})", reset = "never" }`;
+    /// fields which are not specified are copied from environment `parent` if provided.
+    fn from_table(table: &toml::value::Table, parent: Option<&Env>) -> Option<Self> {
+        let get_str = |field: &str| match table.get(field) {
+            Some(toml::Value::String(v)) => Some(v.to_string()),
+            _ => None,
+        };
+        let key = get_str("key")?;
+        let mut env = match parent {
+            Some(parent) => Env {
+                regex: Self::compile_regex(&key, "", DirectiveSyntax::Brackets),
+                key,
+                ..parent.clone()
+            },
+            None => Self::new(&key, &get_str("name")?, "**"),
+        };
+        if let Some(name) = get_str("name") {
+            env.name = name;
+            // the plural of the parent does not apply to another name
+            env.plural = None;
+        }
+        if let Some(plural) = get_str("plural") {
+            env.plural = Some(plural);
+        }
+        if let Some(emph) = get_str("emph") {
+            env.emph = emph;
+        }
+        if let Some(template) = get_str("template") {
+            env.template = Some(template);
+        }
+        if let Some(counter_format) = get_str("counter_format") {
+            env.counter_format = Some(counter_format);
+        }
+        if let Some(header_template) = get_str("header_template") {
+            env.header_template = Some(header_template);
+        }
+        // `counter_group` is an alias of `counter`
+        if let Some(counter) = get_str("counter").or_else(|| get_str("counter_group")) {
+            env.counter = Some(counter);
+        }
+        if let Some(within) = get_str("within") {
+            env.within = Some(within);
+        }
+        // deprecated alias of `reset = "never"`, which `reset` takes precedence over
+        if let Some(toml::Value::Boolean(b)) = table.get("global_counter") {
+            env.reset = if *b { Some(Reset::Never) } else { None };
+            warn!(
+                "The `global_counter' field of environment `{}' is deprecated, use `reset = \"never\"' instead",
+                env.key
+            );
+        }
+        if let Some(numbering_style) = get_str("numbering_style") {
+            match NumberingStyle::from_str(&numbering_style) {
+                Some(numbering_style) => env.numbering_style = Some(numbering_style),
+                None => warn!(
+                    "Unknown numbering style `{numbering_style}' for environment `{}'",
+                    env.key
+                ),
+            }
+        }
+        if let Some(toml::Value::Integer(start)) = table.get("start") {
+            env.set_start(*start);
+        }
+        if let Some(toml::Value::Boolean(b)) = table.get("show_total") {
+            env.show_total = *b;
+        }
+        if let Some(toml::Value::Boolean(b)) = table.get("capture_line") {
+            env.capture_line = *b;
+        }
+        if let Some(toml::Value::Integer(width)) = table.get("pad_width") {
+            match usize::try_from(*width) {
+                Ok(width) => env.pad_width = width,
+                Err(_) => warn!(
+                    "Ignoring negative pad width {width} for environment `{}'",
+                    env.key
+                ),
+            }
+        }
+        if let Some(reset) = get_str("reset") {
+            match Reset::from_str(&reset) {
+                Some(reset) => env.reset = Some(reset),
+                None => warn!(
+                    "Unknown reset policy `{reset}' for environment `{}'",
+                    env.key
+                ),
+            }
+        }
+        match get_str("style").as_deref() {
+            Some("plain") => env.style = Style::Plain,
+            Some("quote") => env.style = Style::Quote,
+            Some(style) => warn!("Unknown style `{style}' for environment `{}'", env.key),
+            None => {}
+        }
+        Some(env)
+    }
+
+    /// Compiles the regex matching `{{key}}{label}[title]{attrs}` (or `{{key | label | title}}{attrs}`
+    /// with the pipe syntax), the key being preceded by `namespace` (see `NumThmPreprocessor::namespace`).
+    fn compile_regex(key: &str, namespace: &str, syntax: DirectiveSyntax) -> Regex {
+        Self::compile_combined_regex(&[key], namespace, syntax)
+    }
+
+    /// Compiles the regex matching `{{key}}{label}[title]{attrs}` (or `{{key | label | title}}{attrs}`
+    /// with the pipe syntax) for any key of `keys`, the key being captured as `key` and preceded by `namespace`
+    /// (see `NumThmPreprocessor::namespace`).
+    fn compile_combined_regex(keys: &[&str], namespace: &str, syntax: DirectiveSyntax) -> Regex {
+        let keys: Vec<String> = keys.iter().map(|key| regex::escape(key)).collect();
+        let mut pattern = r"\{\{".to_string();
+        pattern.push_str(&regex::escape(namespace));
+        pattern.push_str(&format!(
+            r"(?P<key>{})(?:(?P<sub>\+)|(?P<star>\*))?",
+            keys.join("|")
+        ));
+        match syntax {
+            DirectiveSyntax::Brackets => pattern.push_str(
+                r"\}\}(\{(?P<label>(?:\\.|[^=\\}])*)\})?(\[(?P<title>.*?)\])?",
+            ),
+            // the label and the title are trimmed, and may be empty (i.e. not captured)
+            DirectiveSyntax::Pipes => pattern.push_str(
+                r"(?:[ \t]*\|[ \t]*(?P<label>[^|}\s](?:[^|}\n]*[^|}\s])?)?)?(?:[ \t]*\|[ \t]*(?P<title>[^|}\s](?:[^|}\n]*[^|}\s])?)?)?[ \t]*\}\}",
+            ),
+        }
+        pattern.push_str(r"(\{(?P<attrs>[^}]*=[^}]*)\})?");
+        // see https://regex101.com/ for an explanation of the regex
+        // matches {{key}}{label}[title]{attrs} where {label}, [title], and {attrs} are optional,
+        // key may be followed by `+` for sub-numbered environments or by `*` for unnumbered ones,
+        // {label} may contain escaped braces `\{` and `\}`, and {attrs} is a list of attributes key=value
+        Regex::new(pattern.as_str()).unwrap()
+    }
+
+    /// Returns the name of the counter of the environment.
+    fn counter_name(&self) -> &str {
+        self.counter.as_deref().unwrap_or(&self.key)
+    }
+
+    /// Sets the first value of the counter of the environment, which must be positive.
+    fn set_start(&mut self, start: i64) {
+        match usize::try_from(start) {
+            Ok(start) if start > 0 => self.start = start,
+            _ => warn!(
+                "Ignoring non-positive start value {start} for environment `{}'",
+                self.key
+            ),
+        }
+    }
+
+    /// Returns counter value `ctr` formatted according to the numbering style of the environment,
+    /// or `default_style` if it has none, e.g. "iii" or "007".
+    fn format_counter(&self, ctr: usize, default_style: NumberingStyle) -> String {
+        match self.numbering_style.unwrap_or(default_style) {
+            // the prefix is not padded
+            NumberingStyle::Arabic => format!("{:0width$}", ctr, width = self.pad_width),
+            style => format_counter(ctr, style),
+        }
+    }
+
+    /// Returns the plural of the name of the environment, e.g. "Theorems".
+    fn plural(&self) -> String {
+        match &self.plural {
+            Some(plural) => plural.clone(),
+            None => format!("{}s", self.name),
+        }
+    }
+
+    /// Returns the number for formatted counter value `ctr`, e.g. "1.2.1", formatted according to `counter_format`.
+    fn num(&self, prefix: &str, ctr: &str) -> String {
+        let num = format!("{prefix}{ctr}");
+        match &self.counter_format {
+            Some(format) => format.replace("{n}", &num),
+            None => num,
+        }
+    }
+
+    /// Returns the numbered name for formatted counter value `ctr`, e.g. "Theorem 1.2.1",
+    /// the name and the number being separated by `separator` unless a template is given.
+    fn num_name(&self, prefix: &str, ctr: &str, separator: &str) -> String {
+        match &self.template {
+            Some(template) => template
+                .replace("{name}", &self.name)
+                .replace("{prefix}", prefix)
+                .replace("{num}", &self.num(prefix, ctr))
+                .replace("{n}", ctr),
+            None => format!("{}{separator}{}", self.name, self.num(prefix, ctr)),
+        }
+    }
+}
+
+/// The position of a label definition or reference in a chapter source, for the label export.
+#[derive(Debug, PartialEq)]
+struct LabelPosition {
+    /// The label.
+    label: String,
+    /// The key of the environment for a definition, empty for a reference.
+    key: String,
+    /// The path to the chapter source.
+    path: PathBuf,
+    /// The byte offset of the directive in the chapter source.
+    offset: usize,
+    /// The line of the directive, starting at 1.
+    line: usize,
+    /// The column of the directive in characters, starting at 1.
+    column: usize,
+}
+
+/// A problem of a label definition or reference found by the `check` subcommand.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    /// The line of the directive, starting at 1.
+    pub line: usize,
+    /// The column of the directive in characters, starting at 1.
+    pub column: usize,
+    /// The description of the problem, e.g. "Unknown reference `thm:main'".
+    pub message: String,
+    /// Whether the problem can be fixed mechanically (see `CheckedFile::fixed`).
+    pub fixable: bool,
+}
+
+/// The problems found by the `check` subcommand in a chapter source file.
+#[derive(Debug, PartialEq)]
+pub struct CheckedFile {
+    /// The path to the chapter source file.
+    pub path: PathBuf,
+    /// The problems, in order of appearance.
+    pub problems: Vec<Problem>,
+    /// The content of the file with all fixable problems fixed, if there are any.
+    pub fixed: Option<String>,
+    /// The changed lines of the file, e.g. "@@ line 3 @@\n-{{ ref :thm:a}}\n+{{ref: thm:a}}\n".
+    pub diff: String,
+}
+
+/// The configuration of the lint checking that labels start with the key of their environment.
+#[derive(Clone)]
+struct LabelLint {
+    /// The separator between the prefix and the rest of the label, e.g. ":" for "thm:lagrange".
+    separator: String,
+    /// Extra prefixes allowed for each environment key, e.g. "theorem" for "thm".
+    aliases: HashMap<String, Vec<String>>,
+    /// Whether labels without any separator must also be reported.
+    strict: bool,
+}
+
+/// How counter values are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NumberingStyle {
+    /// 1, 2, 3, ...
+    Arabic,
+    /// i, ii, iii, ...
+    RomanLower,
+    /// I, II, III, ...
+    RomanUpper,
+    /// a, b, c, ..., z, aa, ab, ...
+    AlphaLower,
+    /// A, B, C, ..., Z, AA, AB, ...
+    AlphaUpper,
+}
+
+impl NumberingStyle {
+    /// Parses a numbering style from its configuration value (or its short form, e.g. "Roman" for "roman-upper").
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "arabic" => Some(NumberingStyle::Arabic),
+            "roman-lower" | "roman" => Some(NumberingStyle::RomanLower),
+            "roman-upper" | "Roman" => Some(NumberingStyle::RomanUpper),
+            "alpha-lower" | "alpha" => Some(NumberingStyle::AlphaLower),
+            "alpha-upper" | "Alpha" => Some(NumberingStyle::AlphaUpper),
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration value corresponding to the numbering style.
+    fn as_str(&self) -> &'static str {
+        match self {
+            NumberingStyle::Arabic => "arabic",
+            NumberingStyle::RomanLower => "roman-lower",
+            NumberingStyle::RomanUpper => "roman-upper",
+            NumberingStyle::AlphaLower => "alpha-lower",
+            NumberingStyle::AlphaUpper => "alpha-upper",
+        }
+    }
+}
+
+/// How the label and the title of directives are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DirectiveSyntax {
+    /// `{{thm}}{label}[title]`.
+    Brackets,
+    /// `{{thm | label | title}}`.
+    Pipes,
+}
+
+impl DirectiveSyntax {
+    /// Parses a directive syntax from its configuration value.
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "brackets" => Some(DirectiveSyntax::Brackets),
+            "pipes" => Some(DirectiveSyntax::Pipes),
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration value corresponding to the directive syntax.
+    fn as_str(&self) -> &'static str {
+        match self {
+            DirectiveSyntax::Brackets => "brackets",
+            DirectiveSyntax::Pipes => "pipes",
+        }
+    }
+}
+
+/// The attribute of the anchors identifying labeled environments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AnchorAttribute {
+    /// `<a id="label">`.
+    Id,
+    /// `<a name="label">`, deprecated in HTML5 but supported by old renderers.
+    Name,
+    /// `<a id="label" name="label">`.
+    Both,
+}
+
+impl AnchorAttribute {
+    /// Parses an anchor attribute from its configuration value.
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "id" => Some(AnchorAttribute::Id),
+            "name" => Some(AnchorAttribute::Name),
+            "both" => Some(AnchorAttribute::Both),
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration value corresponding to the anchor attribute.
+    fn as_str(&self) -> &'static str {
+        match self {
+            AnchorAttribute::Id => "id",
+            AnchorAttribute::Name => "name",
+            AnchorAttribute::Both => "both",
+        }
+    }
+
+    /// Returns the attributes of an anchor with (percent-encoded) label `label`, e.g. `id="thm:a"`.
+    fn attrs(&self, label: &str) -> String {
+        match self {
+            AnchorAttribute::Id => format!("id=\"{label}\""),
+            AnchorAttribute::Name => format!("name=\"{label}\""),
+            AnchorAttribute::Both => format!("id=\"{label}\" name=\"{label}\""),
+        }
+    }
+}
+
+/// A step of the normalization of labels, applied both to labels of environments and to labels of references.
+#[derive(Clone, Debug, PartialEq)]
+enum LabelOp {
+    /// Converts the label to lowercase.
+    Lowercase,
+    /// Removes leading and trailing whitespace.
+    Trim,
+    /// Removes a prefix of the label, if present.
+    StripPrefix(String),
+    /// Replaces all occurrences of a string by another one.
+    Replace(String, String),
+}
+
+impl LabelOp {
+    /// Parses an operation from its configuration value, either `"lowercase"`, `"trim"`,
+    /// `{ strip_prefix = "prefix" }`, or `{ replace = "from", with = "to" }`.
+    fn from_value(value: &toml::Value) -> Option<Self> {
+        match value {
+            toml::Value::String(op) => match op.as_str() {
+                "lowercase" => Some(LabelOp::Lowercase),
+                "trim" => Some(LabelOp::Trim),
+                _ => None,
+            },
+            toml::Value::Table(table) => match (
+                table.get("strip_prefix").and_then(|v| v.as_str()),
+                table.get("replace").and_then(|v| v.as_str()),
+                table.get("with").and_then(|v| v.as_str()),
+            ) {
+                (Some(prefix), None, None) => Some(LabelOp::StripPrefix(prefix.to_string())),
+                (None, Some(from), Some(to)) => {
+                    Some(LabelOp::Replace(from.to_string(), to.to_string()))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration value corresponding to the operation.
+    fn to_value(&self) -> toml::Value {
+        match self {
+            LabelOp::Lowercase => "lowercase".into(),
+            LabelOp::Trim => "trim".into(),
+            LabelOp::StripPrefix(prefix) => {
+                let mut table = toml::value::Table::new();
+                table.insert("strip_prefix".into(), prefix.as_str().into());
+                table.into()
+            }
+            LabelOp::Replace(from, to) => {
+                let mut table = toml::value::Table::new();
+                table.insert("replace".into(), from.as_str().into());
+                table.insert("with".into(), to.as_str().into());
+                table.into()
+            }
+        }
+    }
+
+    /// Applies the operation to label `label`.
+    fn apply(&self, label: &str) -> String {
+        match self {
+            LabelOp::Lowercase => label.to_lowercase(),
+            LabelOp::Trim => label.trim().to_string(),
+            LabelOp::StripPrefix(prefix) => label
+                .strip_prefix(prefix.as_str())
+                .unwrap_or(label)
+                .to_string(),
+            LabelOp::Replace(from, to) if !from.is_empty() => label.replace(from.as_str(), to),
+            LabelOp::Replace(..) => label.to_string(),
+        }
+    }
+}
+
+/// When counters are reset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reset {
+    /// Counters are reset at each chapter.
+    Chapter,
+    /// Counters are reset at each part of the book, i.e., after each part title of the summary.
+    Part,
+    /// Counters run through the whole book.
+    Never,
+}
+
+impl Reset {
+    /// Parses a reset policy from its configuration value.
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "chapter" => Some(Reset::Chapter),
+            "part" => Some(Reset::Part),
+            "never" => Some(Reset::Never),
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration value corresponding to the reset policy.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Reset::Chapter => "chapter",
+            Reset::Part => "part",
+            Reset::Never => "never",
+        }
+    }
+}
+
+/// The order of the entries of a list of environments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IndexSort {
+    /// Order of appearance in the book.
+    Appearance,
+    /// Alphabetical order of titles, falling back to the numbered name for untitled environments.
+    Title,
+    /// Numerical order of the numbers.
+    Number,
+}
+
+impl IndexSort {
+    /// Returns the configuration value corresponding to the order.
+    fn as_str(&self) -> &'static str {
+        match self {
+            IndexSort::Appearance => "appearance",
+            IndexSort::Title => "title",
+            IndexSort::Number => "number",
+        }
+    }
+}
+
+/// How environments of unnumbered chapters (e.g. prefix and suffix chapters) are numbered when numbers are prefixed.
+#[derive(Clone, Debug, PartialEq)]
+enum UnnumberedChapters {
+    /// Environments are numbered without prefix, e.g. "Theorem 1".
+    Empty,
+    /// The prefix is derived from the file stem of the chapter, e.g. "Theorem preface.1".
+    Stem,
+    /// The prefix is the given string, e.g. "Theorem P.1" for "P.".
+    Fixed(String),
+    /// Environments are not numbered, as starred variants, e.g. "Theorem".
+    Unnumbered,
+}
+
+impl UnnumberedChapters {
+    /// Parses a strategy from its configuration value (fixed prefixes are given by `unnumbered_prefix`).
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "empty" => Some(UnnumberedChapters::Empty),
+            "stem" => Some(UnnumberedChapters::Stem),
+            "unnumbered" => Some(UnnumberedChapters::Unnumbered),
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration value corresponding to the strategy.
+    fn as_str(&self) -> &'static str {
+        match self {
+            UnnumberedChapters::Empty => "empty",
+            UnnumberedChapters::Stem => "stem",
+            UnnumberedChapters::Fixed(_) => "fixed",
+            UnnumberedChapters::Unnumbered => "unnumbered",
+        }
+    }
+}
+
+/// The regexes of a preprocessor depending on its configuration.
+#[derive(Clone)]
+struct Regexes {
+    /// The regex matching all environments, so that they are processed in document order.
+    envs: Regex,
+    /// The regex matching `{{set: key=n ...}}` directives.
+    set: Regex,
+    /// The regex matching directives opening groups of sub-equations.
+    subeq_begin: Regex,
+    /// The regex matching directives closing groups of sub-equations.
+    subeq_end: Regex,
+    /// The regex matching directives opening proof blocks.
+    proof: Regex,
+    /// The regex matching markers ending proof blocks.
+    proof_end: Regex,
+    /// The regex matching references of the enabled types.
+    refs: Regex,
+    /// The regex matching directives which look like references of any type.
+    any_ref: Regex,
+    /// The regex matching `{{listof: ...}}` directives.
+    listof: Regex,
+    /// The regex matching the lead-ins of proof paragraphs, if any lead word is configured.
+    proof_lead: Option<Regex>,
+}
+
+impl Regexes {
+    /// Compiles the regexes for environments `envs`, directives preceded by `namespace`
+    /// (see `NumThmPreprocessor::namespace`) and written with syntax `syntax`, references of types `reftypes`,
+    /// and proof paragraphs starting with one of `lead_words`.
+    fn new(
+        envs: &[Env],
+        namespace: &str,
+        syntax: DirectiveSyntax,
+        reftypes: &[String],
+        lead_words: &[String],
+    ) -> Self {
+        let keys: Vec<&str> = envs.iter().map(|env| env.key.as_str()).collect();
+        let ns = regex::escape(namespace);
+        // see https://regex101.com/ for an explanation of the regexes
+        // the lead-in of a proof ends with the first period followed by a whitespace or the end of the line,
+        // so that it may contain numbers such as "3.2"
+        let proof_lead = (!lead_words.is_empty()).then(|| {
+            let words: Vec<String> = lead_words.iter().map(|word| regex::escape(word)).collect();
+            Regex::new(&format!(
+                r"^(?P<lead>(?:{})(?P<rest>(?:[^.\n]|\.\S)*)\.)(?:\s|$)",
+                words.join("|")
+            ))
+            .unwrap()
+        });
+        Self {
+            envs: Env::compile_combined_regex(&keys, namespace, syntax),
+            set: Regex::new(&format!(
+                r"\{{\{{{ns}{SET}:\s*(?P<assignments>[^}}]*)\}}\}}"
+            ))
+            .unwrap(),
+            subeq_begin: Env::compile_regex(&format!("{SUBEQUATIONS}-begin"), namespace, syntax),
+            subeq_end: Regex::new(&format!(r"\{{\{{{ns}{SUBEQUATIONS}-end\}}\}}")).unwrap(),
+            proof: Env::compile_regex(PROOF, namespace, syntax),
+            proof_end: Regex::new(&format!(r"\{{\{{{ns}(?:{PROOF}-end|end:{PROOF})\}}\}}"))
+                .unwrap(),
+            refs: ref_regex(namespace, reftypes),
+            any_ref: any_ref_regex(namespace),
+            listof: Regex::new(&format!(r"\{{\{{{ns}listof:(?P<keys>[^}}]*)\}}\}}")).unwrap(),
+            proof_lead,
+        }
+    }
 }
 
 /// A preprocessor for automatically numbering theorems, lemmas, etc.
+#[derive(Clone)]
 pub struct NumThmPreprocessor {
     /// The list of environments handled by the preprocessor.
     envs: Vec<Env>,
+    /// The regexes depending on the configuration, compiled once the configuration is read.
+    regexes: Regexes,
     /// Whether theorem numbers must be prefixed by the section number.
     with_prefix: bool,
+    /// How environments of unnumbered chapters are numbered when numbers are prefixed.
+    unnumbered_chapters: UnnumberedChapters,
+    /// Whether unnumbered appendices and suffix chapters are numbered with letters, as numbered appendices.
+    appendix_prefix: bool,
+    /// Whether no environment is numbered, as for starred variants
+    /// (set when processing an unnumbered chapter with the `unnumbered` strategy).
+    all_unnumbered: bool,
+    /// The deepest level of the headings (at least 2) whose numbers within chapters extend the prefix,
+    /// e.g. 2 for "Theorem 4.3.2" after the third `##` heading of chapter 4, if any.
+    heading_depth: Option<usize>,
+    /// When counters are reset, unless the environment specifies its own policy.
+    reset: Reset,
+    /// The number of components of the section number used as prefix, e.g. 1 for "Theorem 2.4"
+    /// in Section 2.3.1; chapters with the same truncated number share their counters.
+    prefix_depth: Option<usize>,
+    /// The offset added to the counters of all environments, whose numbers then start at `number_offset + 1`
+    /// (or after the first value of the counter of the environment) after each reset.
+    number_offset: usize,
+    /// How counters are written, unless the environment specifies its own style.
+    numbering_style: NumberingStyle,
+    /// Glob patterns matching the paths of appendix chapters, numbered with letters.
+    appendix_paths: Vec<String>,
+    /// Glob patterns matching the paths of the chapters whose content is processed (all chapters if empty);
+    /// environments of the other chapters are numbered, but left as is.
+    only_paths: Vec<String>,
+    /// The title of the part of the summary from which chapters are appendices, if any.
+    appendix_part: Option<String>,
+    /// Whether theorem numbers must be prefixed by the file stem of the chapter, e.g. "groups.1" (takes precedence over `with_prefix`).
+    prefix_from_filename: bool,
+    /// Prefixes replacing the prefix of some chapters (which then have their own counters), indexed by the
+    /// path of the chapter relative to the source directory, e.g. "problem-sets/week3.md" => "PS3.".
+    prefix_overrides: HashMap<String, String>,
+    /// Whether labeled headers must be wrapped in an anchor that gets highlighted when targeted.
+    highlight_target: bool,
+    /// The attribute of the anchors identifying labeled environments.
+    anchor_attribute: AnchorAttribute,
+    /// Whether a newline must be prepended to chapters starting with an anchor.
+    leading_newline: bool,
+    /// Whether references must be followed by a page reference placeholder for print output.
+    print_pagerefs: bool,
+    /// Whether `ref` references must be rendered as numbered citations such as "[1]",
+    /// listed with the referenced environments at the end of each chapter.
+    citation_mode: bool,
+    /// Whether a period or comma immediately following a reference must be included in the link text.
+    absorb_trailing_punct: bool,
+    /// Whether links of references must have a tooltip giving the location of the target,
+    /// e.g. "Chapter 3 > Section 3.2 > Theorem 3.2.1".
+    ref_location_tooltip: bool,
+    /// Whether the placeholder of unknown references must be followed by a TODO comment naming the label.
+    todo_unknown_refs: bool,
+    /// The placeholder replacing unknown references, in which `{label}` is replaced by the label, e.g. "**[??]**".
+    missing_ref_text: String,
+    /// An optional template of the text of references, in which `{text}` is replaced by the resolved text,
+    /// e.g. "see {text}" for "see Theorem 1".
+    ref_template: Option<String>,
+    /// The types of references which are processed, e.g. "tref" for `{{tref: label}}`;
+    /// a directive with another type triggers a warning.
+    enabled_reftypes: Vec<String>,
+    /// The separator between references in a reference to several labels, e.g. ", " for "Theorem 1, Lemma 2".
+    ref_join_separator: String,
+    /// The separator before the last reference in a reference to several labels,
+    /// e.g. " and " for "Theorem 1 and Lemma 2".
+    ref_join_last: String,
+    /// Whether `ref_join_separator` must also precede the last reference when there are at least three labels,
+    /// e.g. "Theorem 1, Lemma 2, and Lemma 3".
+    ref_oxford_comma: bool,
+    /// Whether starred variants of environments such as `{{thm*}}`, which are not numbered, are supported.
+    starred_envs: bool,
+    /// Whether unknown references and duplicate labels must make the preprocessor fail rather than emit a warning.
+    strict: bool,
+    /// The separator between the name and the number of environments, e.g. " " for "Theorem 1".
+    name_separator: String,
+    /// The separator between the components of numbers, e.g. "." for "Theorem 1.2.1".
+    separator: String,
+    /// The separator between the prefix and the counter, e.g. "-" for "Theorem 1.2-3" (defaults to `separator`).
+    prefix_sep: String,
+    /// The punctuation terminating headers, e.g. "." for "**Theorem 1.**".
+    header_punct: String,
+    /// Whether the emphasis of headers must be rendered with HTML tags rather than markdown delimiters,
+    /// which CommonMark does not recognize next to some full-width characters.
+    html_emphasis: bool,
+    /// Whether numbered names in headers must be wrapped in an element allowing to copy them to the clipboard
+    /// (HTML renderer only).
+    clipboard_numbers: bool,
+    /// Whether numbered names in headers must be wrapped in an element displayed in the margin (HTML renderer only).
+    margin_tags: bool,
+    /// Whether headers must be wrapped in an element with classes identifying the environment,
+    /// e.g. "numthm-env-thm" for theorems (HTML renderer only).
+    emit_classes: bool,
+    /// The renderers supported by the preprocessor, e.g. "html".
+    renderers: Vec<String>,
+    /// Whether the book is rendered to HTML, in which case raw HTML elements can be emitted
+    /// (set according to the renderer when running the preprocessor).
+    html_renderer: bool,
+    /// The namespace which must precede the names of all directives, e.g. "numthm" for `{{numthm.thm}}`, if any.
+    namespace: Option<String>,
+    /// How the label and the title of directives are written.
+    directive_syntax: DirectiveSyntax,
+    /// Whether paragraphs starting with one of `proof_lead_words` must be formatted as proofs.
+    detect_proofs: bool,
+    /// The words starting the paragraphs detected as proofs, e.g. "Proof".
+    proof_lead_words: Vec<String>,
+    /// Whether proof blocks must be rendered as collapsible HTML `<details>` elements.
+    collapsible_proof: bool,
+    /// Whether the first untitled proof block following a numbered environment (before the next environment)
+    /// must be associated with it, as if it were titled "of label", and environments must link their deferred proofs.
+    link_proofs: bool,
+    /// The order of the entries of lists of environments.
+    index_sort: IndexSort,
+    /// The configuration of the label prefix lint, if enabled.
+    label_lint: Option<LabelLint>,
+    /// Whether labels defined in several chapters must be reported as errors, since anchors collide
+    /// when all chapters are rendered on a single page.
+    global_unique_anchors: bool,
+    /// The path (relative to the book root) of the JSON file to which label positions are exported, if any.
+    export_labels: Option<PathBuf>,
+    /// The path (relative to the book root) of the JSON file from which the initial values of counters are
+    /// imported, if any, e.g. to continue the numbering of a previous volume.
+    counter_import: Option<PathBuf>,
+    /// The path (relative to the book root) of the JSON file to which the final values of counters are
+    /// exported, if any.
+    counter_export: Option<PathBuf>,
+    /// The errors found in the configuration, reported when running the preprocessor.
+    config_errors: Vec<String>,
+    /// The operations successively applied to labels of environments and references, e.g. to make them
+    /// case-insensitive.
+    label_normalization: Vec<LabelOp>,
 }
 
 /// The `LabelInfo` structure contains information for formatting the hyperlink to a specific theorem, lemma, etc.
 #[derive(Debug, PartialEq)]
 struct LabelInfo {
+    /// The key of the environment with the label, e.g. "thm".
+    key: String,
     /// The "numbered name" associated with the label, e.g. "Theorem 1.2.1".
     num_name: String,
+    /// The number associated with the label, e.g. "1.2.1" (empty for unnumbered environments).
+    num: String,
     /// The path to the file containing the environment with the label.
     path: PathBuf,
     /// An optional title.
     title: Option<String>,
+    /// An optional status, e.g. "open" for a conjecture.
+    status: Option<String>,
+    /// The tags of the environment, used to filter lists of environments.
+    tags: Vec<String>,
+    /// The position of the label in the order in which labels were registered.
+    index: usize,
+    /// The paths to the files containing environments redefining the label, which are ignored.
+    redefinitions: Vec<PathBuf>,
+    /// The label as written in the definition, before `label_normalization` is applied.
+    source: String,
+    /// The distinct labels of other definitions normalized to the same label, which are ignored.
+    collisions: Vec<String>,
+    /// The section number of the chapter containing the environment, e.g. `[3, 2]` (empty for unnumbered chapters).
+    section: Vec<u32>,
+    /// Whether the chapter containing the environment is excluded by `only_paths`, so that the environment has
+    /// no anchor and references to it are rendered as plain text.
+    excluded: bool,
 }
 
 impl NumThmPreprocessor {
     pub fn new(ctx: &PreprocessorContext) -> Self {
+        Self::from_config(&ctx.config)
+    }
+
+    /// Creates a preprocessor from the configuration of a book.
+    pub fn from_config(config: &Config) -> Self {
         let mut pre = Self::default();
 
-        if let Some(toml::Value::Boolean(b)) = ctx.config.get("preprocessor.numthm.prefix") {
-            pre.with_prefix = *b;
+        // presets for Chinese, Japanese, and Korean books, which can be overridden below
+        let language = config.book.language.as_deref().unwrap_or_default();
+        match language.split(['-', '_']).next() {
+            Some("zh") => {
+                pre.header_punct = "。".to_string();
+                pre.html_emphasis = true;
+            }
+            Some("ja") => {
+                pre.name_separator = String::new();
+                pre.header_punct = "。".to_string();
+                pre.html_emphasis = true;
+            }
+            Some("ko") => pre.html_emphasis = true,
+            _ => {}
+        }
+
+        if let Some(toml::Value::String(separator)) =
+            config.get("preprocessor.numthm.name_separator")
+        {
+            pre.name_separator = separator.clone();
         }
 
-        if let Some(toml::Value::Array(array)) = ctx.config.get("preprocessor.numthm.custom_environments") {
-            for array_entry in array {
-                if let toml::Value::Array(env_params) = array_entry {
-                    if let [toml::Value::String(key), toml::Value::String(name), toml::Value::String(emph)] = &env_params[0..3] {
-                        pre.envs.push(Env {
-                            key: key.to_string(),
-                            name: name.to_string(),
-                            emph: emph.to_string(),
-                        })
+        if let Some(toml::Value::String(separator)) = config.get("preprocessor.numthm.separator") {
+            pre.separator = separator.clone();
+            pre.prefix_sep = separator.clone();
+        }
+
+        if let Some(toml::Value::String(separator)) =
+            config.get("preprocessor.numthm.prefix_separator")
+        {
+            pre.prefix_sep = separator.clone();
+        }
+
+        // shorthand for an empty `header_punct`, which takes precedence
+        if let Some(toml::Value::Boolean(false)) = config.get("preprocessor.numthm.trailing_period")
+        {
+            pre.header_punct = String::new();
+        }
+
+        if let Some(toml::Value::String(punct)) = config.get("preprocessor.numthm.header_punct") {
+            pre.header_punct = punct.clone();
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.html_emphasis") {
+            pre.html_emphasis = *b;
+        }
+
+        match config.get("preprocessor.numthm.prefix") {
+            Some(toml::Value::Boolean(b)) => pre.with_prefix = *b,
+            Some(toml::Value::String(prefix)) if prefix == "headings" => {
+                pre.with_prefix = true;
+                pre.heading_depth = Some(2);
+            }
+            Some(prefix) => warn!("Unknown prefix {prefix}"),
+            None => {}
+        }
+
+        if let Some(toml::Value::String(strategy)) =
+            config.get("preprocessor.numthm.unnumbered_chapters")
+        {
+            match UnnumberedChapters::from_str(strategy) {
+                Some(strategy) => pre.unnumbered_chapters = strategy,
+                None => warn!("Unknown strategy `{strategy}' for unnumbered chapters"),
+            }
+        }
+
+        // shorthand for a fixed prefix of unnumbered chapters
+        if let Some(toml::Value::String(prefix)) =
+            config.get("preprocessor.numthm.unnumbered_prefix")
+        {
+            pre.unnumbered_chapters = UnnumberedChapters::Fixed(prefix.clone());
+        }
+
+        if let (Some(toml::Value::Integer(depth)), Some(_)) = (
+            config.get("preprocessor.numthm.heading_depth"),
+            pre.heading_depth,
+        ) {
+            match usize::try_from(*depth) {
+                Ok(depth) if (2..=6).contains(&depth) => pre.heading_depth = Some(depth),
+                _ => warn!("Ignoring heading depth {depth}, which must be between 2 and 6"),
+            }
+        }
+
+        if let Some(toml::Value::Integer(depth)) = config.get("preprocessor.numthm.prefix_depth") {
+            match usize::try_from(*depth) {
+                Ok(depth) if depth > 0 => pre.prefix_depth = Some(depth),
+                _ => warn!("Ignoring non-positive prefix depth {depth}"),
+            }
+        }
+
+        if let Some(toml::Value::Integer(offset)) = config.get("preprocessor.numthm.number_offset")
+        {
+            match usize::try_from(*offset) {
+                Ok(offset) => pre.number_offset = offset,
+                _ => warn!("Ignoring negative number offset {offset}"),
+            }
+        }
+
+        if let Some(toml::Value::String(numbering_style)) =
+            config.get("preprocessor.numthm.numbering_style")
+        {
+            match NumberingStyle::from_str(numbering_style) {
+                Some(numbering_style) => pre.numbering_style = numbering_style,
+                None => warn!("Unknown numbering style `{numbering_style}'"),
+            }
+        }
+
+        // deprecated aliases of `reset`, which takes precedence over them
+        if let Some(toml::Value::String(numbering)) = config.get("preprocessor.numthm.numbering") {
+            match numbering.as_str() {
+                "chapter" => pre.reset = Reset::Chapter,
+                "continuous" => pre.reset = Reset::Never,
+                _ => warn!("Unknown numbering `{numbering}'"),
+            }
+            warn!(
+                "The `numbering' option is deprecated, use `reset = \"{}\"' instead",
+                pre.reset.as_str()
+            );
+        }
+        for alias in ["continuous", "global_counter"] {
+            if let Some(value) = config.get(&format!("preprocessor.numthm.{alias}")) {
+                if value.as_bool() == Some(true) {
+                    pre.reset = Reset::Never;
+                }
+                warn!("The `{alias}' option is deprecated, use `reset = \"never\"' instead");
+            }
+        }
+
+        if let Some(toml::Value::String(reset)) = config.get("preprocessor.numthm.reset") {
+            match Reset::from_str(reset) {
+                Some(reset) => pre.reset = reset,
+                None => warn!("Unknown reset policy `{reset}'"),
+            }
+        }
+
+        if let Some(toml::Value::Array(patterns)) = config.get("preprocessor.numthm.appendix_paths")
+        {
+            pre.appendix_paths = patterns
+                .iter()
+                .filter_map(|pattern| pattern.as_str().map(String::from))
+                .collect();
+        }
+
+        if let Some(toml::Value::Array(patterns)) = config.get("preprocessor.numthm.only_paths") {
+            pre.only_paths = patterns
+                .iter()
+                .filter_map(|pattern| pattern.as_str().map(String::from))
+                .collect();
+        }
+
+        if let Some(toml::Value::Table(overrides)) =
+            config.get("preprocessor.numthm.prefix_overrides")
+        {
+            for (path, prefix) in overrides {
+                match prefix.as_str() {
+                    Some(prefix) => {
+                        pre.prefix_overrides
+                            .insert(path.clone(), prefix.to_string());
                     }
+                    None => warn!("Invalid prefix override for `{path}': {prefix}"),
                 }
             }
         }
 
-        pre
+        // shorthand for the glob pattern matching all paths starting with the given prefix
+        if let Some(toml::Value::String(start)) = config.get("preprocessor.numthm.appendix_start") {
+            pre.appendix_paths.push(format!("{start}**"));
+        }
+
+        if let Some(toml::Value::String(part)) = config.get("preprocessor.numthm.appendix_part") {
+            pre.appendix_part = Some(part.clone());
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.appendix_prefix") {
+            pre.appendix_prefix = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) =
+            config.get("preprocessor.numthm.prefix_from_filename")
+        {
+            pre.prefix_from_filename = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.highlight_target") {
+            pre.highlight_target = *b;
+        }
+
+        if let Some(toml::Value::String(attribute)) =
+            config.get("preprocessor.numthm.anchor_attribute")
+        {
+            match AnchorAttribute::from_str(attribute) {
+                Some(attribute) => pre.anchor_attribute = attribute,
+                None => warn!("Unknown anchor attribute `{attribute}'"),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.leading_newline") {
+            pre.leading_newline = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.print_pagerefs") {
+            pre.print_pagerefs = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.citation_mode") {
+            pre.citation_mode = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) =
+            config.get("preprocessor.numthm.absorb_trailing_punct")
+        {
+            pre.absorb_trailing_punct = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) =
+            config.get("preprocessor.numthm.ref_location_tooltip")
+        {
+            pre.ref_location_tooltip = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.todo_unknown_refs") {
+            pre.todo_unknown_refs = *b;
+        }
+
+        if let Some(toml::Value::String(text)) = config.get("preprocessor.numthm.missing_ref_text")
+        {
+            pre.missing_ref_text = text.clone();
+        }
+
+        if let Some(toml::Value::String(template)) = config.get("preprocessor.numthm.ref_template")
+        {
+            if template.contains("{text}") {
+                pre.ref_template = Some(template.clone());
+            } else {
+                warn!("Ignoring reference template `{template}' without `{{text}}' placeholder");
+            }
+        }
+
+        if let Some(toml::Value::Array(reftypes)) =
+            config.get("preprocessor.numthm.enabled_reftypes")
+        {
+            pre.enabled_reftypes = Vec::new();
+            for reftype in reftypes {
+                match reftype.as_str() {
+                    Some(r) if REFTYPES.contains(&r) => pre.enabled_reftypes.push(r.to_string()),
+                    _ => {
+                        warn!("Unknown reference type {reftype} in `enabled_reftypes', ignoring it")
+                    }
+                }
+            }
+        }
+
+        if let Some(toml::Value::String(sep)) = config.get("preprocessor.numthm.ref_join_separator")
+        {
+            pre.ref_join_separator = sep.clone();
+        }
+
+        if let Some(toml::Value::String(sep)) = config.get("preprocessor.numthm.ref_join_last") {
+            pre.ref_join_last = sep.clone();
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.ref_oxford_comma") {
+            pre.ref_oxford_comma = *b;
+        }
+
+        // also read by mdbook, which then does not ask the preprocessor whether it supports the renderer
+        if let Some(toml::Value::Array(renderers)) = config.get("preprocessor.numthm.renderers") {
+            pre.renderers = renderers
+                .iter()
+                .filter_map(|renderer| renderer.as_str().map(String::from))
+                .collect();
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.starred_envs") {
+            pre.starred_envs = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.strict") {
+            pre.strict = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.clipboard_numbers") {
+            pre.clipboard_numbers = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.margin_tags") {
+            pre.margin_tags = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.emit_classes") {
+            pre.emit_classes = *b;
+        }
+
+        if let Some(toml::Value::String(namespace)) = config.get("preprocessor.numthm.namespace") {
+            pre.namespace = Some(namespace.clone());
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.detect_proofs") {
+            pre.detect_proofs = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.collapsible_proof") {
+            pre.collapsible_proof = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numthm.link_proofs") {
+            pre.link_proofs = *b;
+        }
+
+        if let Some(toml::Value::Array(words)) = config.get("preprocessor.numthm.proof_lead_words")
+        {
+            pre.proof_lead_words = words
+                .iter()
+                .filter_map(|word| word.as_str().map(String::from))
+                .collect();
+        }
+
+        if let Some(toml::Value::String(sort)) = config.get("preprocessor.numthm.index_sort") {
+            match sort.as_str() {
+                "appearance" => pre.index_sort = IndexSort::Appearance,
+                "title" => pre.index_sort = IndexSort::Title,
+                "number" => pre.index_sort = IndexSort::Number,
+                _ => warn!("Unknown index sort `{sort}'"),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(true)) =
+            config.get("preprocessor.numthm.lint_label_prefixes")
+        {
+            let mut lint = LabelLint {
+                separator: ":".to_string(),
+                aliases: HashMap::new(),
+                strict: false,
+            };
+            if let Some(toml::Value::String(sep)) =
+                config.get("preprocessor.numthm.label_prefix_separator")
+            {
+                lint.separator = sep.to_string();
+            }
+            if let Some(toml::Value::Table(table)) =
+                config.get("preprocessor.numthm.label_prefix_aliases")
+            {
+                for (key, value) in table {
+                    if let toml::Value::Array(aliases) = value {
+                        let aliases = aliases.iter().filter_map(|a| a.as_str());
+                        lint.aliases
+                            .insert(key.to_string(), aliases.map(|a| a.to_string()).collect());
+                    }
+                }
+            }
+            if let Some(toml::Value::Boolean(b)) =
+                config.get("preprocessor.numthm.label_prefix_strict")
+            {
+                lint.strict = *b;
+            }
+            pre.label_lint = Some(lint);
+        }
+
+        if let Some(toml::Value::Boolean(b)) =
+            config.get("preprocessor.numthm.global_unique_anchors")
+        {
+            pre.global_unique_anchors = *b;
+        }
+
+        if let Some(toml::Value::String(path)) = config.get("preprocessor.numthm.export_labels") {
+            pre.export_labels = Some(path.into());
+        }
+
+        if let Some(toml::Value::String(path)) = config.get("preprocessor.numthm.counter_import") {
+            pre.counter_import = Some(path.into());
+        }
+
+        if let Some(toml::Value::String(path)) = config.get("preprocessor.numthm.counter_export") {
+            pre.counter_export = Some(path.into());
+        }
+
+        if let Some(toml::Value::Array(ops)) = config.get("preprocessor.numthm.label_normalization")
+        {
+            for op in ops {
+                match LabelOp::from_value(op) {
+                    Some(op) => pre.label_normalization.push(op),
+                    None => warn!("Unknown label normalization operation {op}"),
+                }
+            }
+        }
+
+        // the markup of equation tags, written as a header template of the builtin equation environment
+        if let Some(toml::Value::String(tag)) = config.get("preprocessor.numthm.eq_tag") {
+            if let Some(eq) = pre.envs.iter_mut().find(|env| env.key == "eq") {
+                eq.header_template = Some(tag.clone());
+            }
+        }
+
+        if let Some(toml::Value::Array(array)) =
+            config.get("preprocessor.numthm.custom_environments")
+        {
+            // environments defined by tables, which may inherit from one another
+            let tables: HashMap<&str, &toml::value::Table> = array
+                .iter()
+                .filter_map(|entry| entry.as_table())
+                .filter_map(|table| Some((table.get("key")?.as_str()?, table)))
+                .collect();
+            let builtins = pre.envs.clone();
+            // the counter groups given as bare strings in arrays, with the key of their environment
+            let mut bare_groups: Vec<(String, String)> = Vec::new();
+            for array_entry in array {
+                match array_entry {
+                    toml::Value::Array(env_params) => {
+                        if let [toml::Value::String(key), toml::Value::String(name), toml::Value::String(emph), rest @ ..] =
+                            env_params.as_slice()
+                        {
+                            let mut env = Env::new(key, name, emph);
+                            // optional trailing elements
+                            for param in rest {
+                                if let toml::Value::Integer(start) = param {
+                                    env.set_start(*start);
+                                } else if let Some(group) =
+                                    param.as_str().and_then(|p| p.strip_prefix("counter="))
+                                {
+                                    env.counter = Some(group.to_string());
+                                } else if let Some(numbering_style) =
+                                    param.as_str().and_then(NumberingStyle::from_str)
+                                {
+                                    env.numbering_style = Some(numbering_style);
+                                } else if let toml::Value::String(group) = param {
+                                    // any other string is the name of a counter group
+                                    env.counter = Some(group.clone());
+                                    bare_groups.push((key.clone(), group.clone()));
+                                } else {
+                                    warn!("Ignoring unknown parameter `{param}' of environment `{key}'");
+                                }
+                            }
+                            pre.define_env(env)
+                        }
+                    }
+                    toml::Value::Table(table) => {
+                        match resolve_env(table, &tables, &builtins, &mut Vec::new()) {
+                            Some(env) => pre.define_env(env),
+                            None => warn!("Invalid custom environment: {array_entry}"),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // a counter group of a single environment is useless, and most likely a misspelled numbering style
+            for (key, group) in bare_groups {
+                if !pre
+                    .envs
+                    .iter()
+                    .any(|env| env.key != key && env.counter_name() == group)
+                {
+                    warn!(
+                        "Counter group `{group}' of environment `{key}' is not shared with any other environment \
+                        (misspelled numbering style?), write `counter={group}' to silence this warning"
+                    );
+                }
+            }
+        }
+
+        // groups of environment keys sharing a counter, named after the first key of the group
+        let groups: Vec<Vec<String>> = match config.get("preprocessor.numthm.shared_counter") {
+            // all environments, except equations (numbered separately, as in LaTeX)
+            // and environments numbered throughout the book
+            Some(toml::Value::Boolean(true)) => vec![pre
+                .envs
+                .iter()
+                .filter(|env| env.key != "eq" && env.reset != Some(Reset::Never))
+                .map(|env| env.key.clone())
+                .collect()],
+            Some(toml::Value::Array(array)) => {
+                let group = |array: &[toml::Value]| -> Vec<String> {
+                    array
+                        .iter()
+                        .filter_map(|key| key.as_str().map(String::from))
+                        .collect()
+                };
+                if array.iter().all(|entry| entry.is_array()) {
+                    // several groups, e.g. [["thm", "lem"], ["def", "rem"]]
+                    array
+                        .iter()
+                        .filter_map(|entry| entry.as_array())
+                        .map(|entry| group(entry))
+                        .collect()
+                } else {
+                    vec![group(array)]
+                }
+            }
+            _ => Vec::new(),
+        };
+        for group in groups.iter().filter(|group| !group.is_empty()) {
+            // environments with an explicit counter keep it
+            for env in pre.envs.iter_mut().filter(|env| env.counter.is_none()) {
+                if group.contains(&env.key) {
+                    env.counter = Some(group[0].clone());
+                }
+            }
+        }
+
+        // custom environments numbered like another environment share its counter,
+        // which is resolved once all environments and counter groups are known
+        if let Some(toml::Value::Array(array)) =
+            config.get("preprocessor.numthm.custom_environments")
+        {
+            let targets: Vec<(&str, &str)> = array
+                .iter()
+                .filter_map(|entry| entry.as_table())
+                .filter_map(|table| {
+                    Some((
+                        table.get("key")?.as_str()?,
+                        table.get("numbered_like")?.as_str()?,
+                    ))
+                })
+                .collect();
+            for &(key, target) in &targets {
+                // targets may themselves be numbered like another environment
+                let mut chain = vec![key];
+                let mut last = target;
+                while let Some(&(_, next)) = targets.iter().find(|(key, _)| *key == last) {
+                    if chain.contains(&last) {
+                        break;
+                    }
+                    chain.push(last);
+                    last = next;
+                }
+                if chain.contains(&last) {
+                    // reported once, by the smallest key of the cycle
+                    if last == key && chain.iter().all(|k| *k >= key) {
+                        pre.config_errors.push(format!(
+                            "Cycle in `numbered_like': {} -> {last}",
+                            chain.join(" -> ")
+                        ));
+                    }
+                    continue;
+                }
+                match pre.envs.iter().find(|env| env.key == last) {
+                    Some(target) => {
+                        let counter = target.counter_name().to_string();
+                        if let Some(env) = pre.envs.iter_mut().find(|env| env.key == key) {
+                            env.counter = Some(counter);
+                        }
+                    }
+                    None => {
+                        let error = format!(
+                            "Unknown environment `{last}' in `numbered_like' of environment `{}'",
+                            chain[chain.len() - 1]
+                        );
+                        if !pre.config_errors.contains(&error) {
+                            pre.config_errors.push(error);
+                        }
+                    }
+                }
+            }
+        }
+
+        // environments which are not active are not processed at all, once their counters are resolved
+        if let Some(toml::Value::Array(keys)) =
+            config.get("preprocessor.numthm.active_environments")
+        {
+            let keys: Vec<&str> = keys.iter().filter_map(|key| key.as_str()).collect();
+            for key in &keys {
+                if !pre.envs.iter().any(|env| env.key == *key) {
+                    warn!("Unknown environment `{key}' in `active_environments'");
+                }
+            }
+            pre.envs.retain(|env| keys.contains(&env.key.as_str()));
+        }
+
+        if let Some(toml::Value::String(syntax)) =
+            config.get("preprocessor.numthm.directive_syntax")
+        {
+            match DirectiveSyntax::from_str(syntax) {
+                Some(syntax) => pre.directive_syntax = syntax,
+                None => warn!("Unknown directive syntax `{syntax}'"),
+            }
+        }
+
+        // the regexes are compiled without namespace and with the bracket syntax when environments are created
+        let namespace = pre.namespace();
+        if !namespace.is_empty() || pre.directive_syntax != DirectiveSyntax::Brackets {
+            for env in &mut pre.envs {
+                env.regex = Env::compile_regex(&env.key, &namespace, pre.directive_syntax);
+            }
+        }
+        pre.regexes = Regexes::new(
+            &pre.envs,
+            &namespace,
+            pre.directive_syntax,
+            &pre.enabled_reftypes,
+            &pre.proof_lead_words,
+        );
+
+        pre
+    }
+}
+
+impl NumThmPreprocessor {
+    /// Returns the resolved configuration of the preprocessor, including all environments, in TOML format.
+    pub fn dump_config(&self) -> String {
+        let mut config = toml::value::Table::new();
+        match self.heading_depth {
+            Some(depth) => {
+                config.insert("prefix".into(), "headings".into());
+                config.insert("heading_depth".into(), (depth as i64).into());
+            }
+            None => {
+                config.insert("prefix".into(), self.with_prefix.into());
+            }
+        }
+        config.insert(
+            "unnumbered_chapters".into(),
+            self.unnumbered_chapters.as_str().into(),
+        );
+        if let UnnumberedChapters::Fixed(prefix) = &self.unnumbered_chapters {
+            config.insert("unnumbered_prefix".into(), prefix.as_str().into());
+        }
+        config.insert("reset".into(), self.reset.as_str().into());
+        if let Some(depth) = self.prefix_depth {
+            config.insert("prefix_depth".into(), (depth as i64).into());
+        }
+        config.insert("number_offset".into(), (self.number_offset as i64).into());
+        config.insert(
+            "numbering_style".into(),
+            self.numbering_style.as_str().into(),
+        );
+        config.insert("appendix_paths".into(), self.appendix_paths.clone().into());
+        if !self.only_paths.is_empty() {
+            config.insert("only_paths".into(), self.only_paths.clone().into());
+        }
+        if let Some(part) = &self.appendix_part {
+            config.insert("appendix_part".into(), part.as_str().into());
+        }
+        config.insert("appendix_prefix".into(), self.appendix_prefix.into());
+        config.insert(
+            "prefix_from_filename".into(),
+            self.prefix_from_filename.into(),
+        );
+        if !self.prefix_overrides.is_empty() {
+            let overrides: toml::value::Table = self
+                .prefix_overrides
+                .iter()
+                .map(|(path, prefix)| (path.clone(), prefix.as_str().into()))
+                .collect();
+            config.insert("prefix_overrides".into(), overrides.into());
+        }
+        config.insert("highlight_target".into(), self.highlight_target.into());
+        config.insert(
+            "anchor_attribute".into(),
+            self.anchor_attribute.as_str().into(),
+        );
+        config.insert("leading_newline".into(), self.leading_newline.into());
+        config.insert("print_pagerefs".into(), self.print_pagerefs.into());
+        config.insert("citation_mode".into(), self.citation_mode.into());
+        config.insert(
+            "absorb_trailing_punct".into(),
+            self.absorb_trailing_punct.into(),
+        );
+        config.insert(
+            "ref_location_tooltip".into(),
+            self.ref_location_tooltip.into(),
+        );
+        config.insert("todo_unknown_refs".into(), self.todo_unknown_refs.into());
+        config.insert(
+            "missing_ref_text".into(),
+            self.missing_ref_text.as_str().into(),
+        );
+        if let Some(template) = &self.ref_template {
+            config.insert("ref_template".into(), template.as_str().into());
+        }
+        config.insert(
+            "enabled_reftypes".into(),
+            self.enabled_reftypes.clone().into(),
+        );
+        config.insert(
+            "ref_join_separator".into(),
+            self.ref_join_separator.as_str().into(),
+        );
+        config.insert("ref_join_last".into(), self.ref_join_last.as_str().into());
+        config.insert("ref_oxford_comma".into(), self.ref_oxford_comma.into());
+        config.insert("renderers".into(), self.renderers.clone().into());
+        config.insert("starred_envs".into(), self.starred_envs.into());
+        config.insert("strict".into(), self.strict.into());
+        config.insert("name_separator".into(), self.name_separator.as_str().into());
+        config.insert("separator".into(), self.separator.as_str().into());
+        config.insert("prefix_separator".into(), self.prefix_sep.as_str().into());
+        config.insert("header_punct".into(), self.header_punct.as_str().into());
+        config.insert("html_emphasis".into(), self.html_emphasis.into());
+        config.insert("clipboard_numbers".into(), self.clipboard_numbers.into());
+        config.insert("margin_tags".into(), self.margin_tags.into());
+        config.insert("emit_classes".into(), self.emit_classes.into());
+        if let Some(namespace) = &self.namespace {
+            config.insert("namespace".into(), namespace.as_str().into());
+        }
+        config.insert(
+            "directive_syntax".into(),
+            self.directive_syntax.as_str().into(),
+        );
+        config.insert("detect_proofs".into(), self.detect_proofs.into());
+        config.insert("collapsible_proof".into(), self.collapsible_proof.into());
+        config.insert("link_proofs".into(), self.link_proofs.into());
+        config.insert(
+            "proof_lead_words".into(),
+            self.proof_lead_words.clone().into(),
+        );
+        config.insert("index_sort".into(), self.index_sort.as_str().into());
+        if let Some(lint) = &self.label_lint {
+            config.insert("lint_label_prefixes".into(), true.into());
+            config.insert(
+                "label_prefix_separator".into(),
+                lint.separator.as_str().into(),
+            );
+            let aliases: toml::value::Table = lint
+                .aliases
+                .iter()
+                .map(|(key, aliases)| (key.clone(), aliases.clone().into()))
+                .collect();
+            config.insert("label_prefix_aliases".into(), aliases.into());
+            config.insert("label_prefix_strict".into(), lint.strict.into());
+        }
+        config.insert(
+            "global_unique_anchors".into(),
+            self.global_unique_anchors.into(),
+        );
+        if let Some(path) = &self.export_labels {
+            config.insert("export_labels".into(), path.display().to_string().into());
+        }
+        if let Some(path) = &self.counter_import {
+            config.insert("counter_import".into(), path.display().to_string().into());
+        }
+        if let Some(path) = &self.counter_export {
+            config.insert("counter_export".into(), path.display().to_string().into());
+        }
+        if !self.label_normalization.is_empty() {
+            let ops: Vec<toml::Value> = self
+                .label_normalization
+                .iter()
+                .map(LabelOp::to_value)
+                .collect();
+            config.insert("label_normalization".into(), ops.into());
+        }
+        let envs: Vec<toml::Value> = self
+            .envs
+            .iter()
+            .map(|env| {
+                let mut table = toml::value::Table::new();
+                table.insert("key".into(), env.key.as_str().into());
+                table.insert("name".into(), env.name.as_str().into());
+                table.insert("emph".into(), env.emph.as_str().into());
+                if let Some(template) = &env.template {
+                    table.insert("template".into(), template.as_str().into());
+                }
+                if let Some(header_template) = &env.header_template {
+                    table.insert("header_template".into(), header_template.as_str().into());
+                }
+                if let Some(counter_format) = &env.counter_format {
+                    table.insert("counter_format".into(), counter_format.as_str().into());
+                }
+                if let Some(plural) = &env.plural {
+                    table.insert("plural".into(), plural.as_str().into());
+                }
+                if let Some(counter) = &env.counter {
+                    table.insert("counter".into(), counter.as_str().into());
+                }
+                if let Some(within) = &env.within {
+                    table.insert("within".into(), within.as_str().into());
+                }
+                if let Some(reset) = env.reset {
+                    table.insert("reset".into(), reset.as_str().into());
+                }
+                if let Some(numbering_style) = env.numbering_style {
+                    table.insert("numbering_style".into(), numbering_style.as_str().into());
+                }
+                if env.start != 1 {
+                    table.insert("start".into(), (env.start as i64).into());
+                }
+                if env.show_total {
+                    table.insert("show_total".into(), true.into());
+                }
+                if env.capture_line {
+                    table.insert("capture_line".into(), true.into());
+                }
+                if env.pad_width != 0 {
+                    table.insert("pad_width".into(), (env.pad_width as i64).into());
+                }
+                table.insert("style".into(), env.style.as_str().into());
+                table.into()
+            })
+            .collect();
+        config.insert("environments".into(), envs.into());
+        toml::to_string(&toml::Value::Table(config)).unwrap()
+    }
+
+    /// Adds environment `env`, replacing any environment with the same key (e.g. a builtin one).
+    fn define_env(&mut self, env: Env) {
+        match self.envs.iter_mut().find(|e| e.key == env.key) {
+            Some(e) => *e = env,
+            None => self.envs.push(env),
+        }
+    }
+
+    /// Returns the string preceding the names of directives, e.g. "numthm." for `{{numthm.thm}}`,
+    /// which is empty if no namespace is configured.
+    fn namespace(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{namespace}."),
+            None => String::new(),
+        }
+    }
+
+    /// Returns whether label `label` is defined in `s`.
+    fn defines_label(&self, s: &str, label: &str) -> bool {
+        self.envs
+            .iter()
+            .any(|env| find_labels(&env.regex, s).iter().any(|(l, _)| l == label))
+    }
+
+    /// Renames label `old` to `new` in the definition and all references contained in `s`
+    /// and returns the result together with the number of renamed occurrences.
+    fn rename_label(&self, s: &str, old: &str, new: &str) -> (String, usize) {
+        let mut total = 0;
+        let mut output = s.to_string();
+        for env in &self.envs {
+            let count;
+            (output, count) = rename_in_matches(&env.regex, &output, old, new, false);
+            total += count;
+        }
+        // references may list several labels
+        let count;
+        (output, count) = rename_in_matches(&self.regexes.refs, &output, old, new, true);
+        (output, total + count)
+    }
+
+    /// Returns the labels defined in `s` in order of appearance, as in references (i.e. trimmed, unescaped,
+    /// and normalized), together with the labels as written (trimmed) and the byte offsets of their directives.
+    fn defined_labels(&self, s: &str) -> Vec<(String, String, usize)> {
+        let mut labels: Vec<(String, String, usize)> = self
+            .envs
+            .iter()
+            .flat_map(|env| find_labels(&env.regex, s))
+            .map(|(label, offset)| {
+                let source = label.trim().to_string();
+                (
+                    normalize_label(&source, &self.label_normalization),
+                    source,
+                    offset,
+                )
+            })
+            .collect();
+        labels.sort_by_key(|(_, _, offset)| *offset);
+        labels
+    }
+
+    /// Returns the labels written in `labels` (the labels defined in the book, indexed as in references) which
+    /// reference `label` may have been meant to refer to: the labels equal to it up to case,
+    /// or else the labels with the same part after the prefix separator whose prefix is another prefix of the
+    /// same environment (see `label_prefix_aliases`), e.g. `thm:main` for `theorem:main`.
+    fn label_candidates<'a>(
+        &self,
+        label: &str,
+        labels: &'a HashMap<String, (PathBuf, usize, String)>,
+    ) -> Vec<&'a str> {
+        let mut candidates: Vec<&str> = labels
+            .iter()
+            .filter(|(known, _)| known.to_lowercase() == label.to_lowercase())
+            .map(|(_, (_, _, source))| source.as_str())
+            .collect();
+        if let (true, Some(lint)) = (candidates.is_empty(), &self.label_lint) {
+            // the environment key of each prefix
+            let key_of = |prefix: &str| {
+                self.envs.iter().map(|env| &env.key).find(|key| {
+                    *key == prefix
+                        || lint
+                            .aliases
+                            .get(*key)
+                            .is_some_and(|a| a.iter().any(|a| a == prefix))
+                })
+            };
+            if let Some((prefix, rest)) = label.split_once(&lint.separator) {
+                if let Some(key) = key_of(prefix) {
+                    candidates = labels
+                        .iter()
+                        .filter(|(known, _)| {
+                            known.split_once(&lint.separator).is_some_and(|(p, r)| {
+                                r == rest && p != prefix && key_of(p) == Some(key)
+                            })
+                        })
+                        .map(|(_, (_, _, source))| source.as_str())
+                        .collect();
+                }
+            }
+        }
+        candidates.sort();
+        candidates
+    }
+
+    /// Checks the label definitions and references of `s` (the content of the chapter source file `path`)
+    /// against the labels defined in the book (see `check_book`) and returns the problems found,
+    /// together with the edits fixing the fixable ones.
+    fn check_content(
+        &self,
+        s: &str,
+        path: &Path,
+        labels: &HashMap<String, (PathBuf, usize, String)>,
+    ) -> (Vec<Problem>, Vec<(std::ops::Range<usize>, String)>) {
+        let mut problems = Vec::new();
+        let mut edits = Vec::new();
+        let mut problem = |offset: usize, message: String, fixable: bool| {
+            let (line, column) = line_and_column(s, offset);
+            problems.push(Problem {
+                line,
+                column,
+                message,
+                fixable,
+            });
+        };
+        let loose_re = loose_ref_regex(&self.namespace());
+        let mut offset = 0;
+        for (untouched, segment) in split_untouched_regions(s) {
+            if untouched {
+                offset += segment.len();
+                continue;
+            }
+            for env in &self.envs {
+                for caps in env.regex.captures_iter(segment) {
+                    let Some(label) = caps.name("label") else {
+                        continue;
+                    };
+                    let start = offset + caps.get(0).unwrap().start();
+                    let raw = label.as_str();
+                    let source = unescape(raw.trim());
+                    let normalized = normalize_label(&source, &self.label_normalization);
+                    match labels.get(&normalized) {
+                        Some((first, first_offset, _))
+                            if (first.as_path(), *first_offset) != (path, start) =>
+                        {
+                            problem(
+                                start,
+                                format!("Label `{source}' already defined in {}", first.display()),
+                                false,
+                            );
+                        }
+                        _ => {}
+                    }
+                    if !raw.trim().is_empty() && raw.trim() != raw {
+                        problem(start, format!("Whitespace around label `{source}'"), true);
+                        let label_start = offset + label.start();
+                        edits.push((label_start..label_start + raw.len(), raw.trim().to_string()));
+                    }
+                }
+            }
+            for caps in loose_re.captures_iter(segment) {
+                let reftype = &caps["reftype"];
+                if !self.enabled_reftypes.iter().any(|r| r == reftype) {
+                    continue;
+                }
+                let whole = caps.get(0).unwrap();
+                let start = offset + whole.start();
+                let directive = whole.as_str();
+                let label_list = &caps["label"];
+                // `{{ ref :label}}` is not recognized as a reference
+                let spacing = !caps["lead"].is_empty() || !caps["colon"].is_empty();
+                if spacing {
+                    problem(start, format!("Spacing of `{directive}'"), true);
+                }
+                if label_list.trim_end() != label_list {
+                    problem(
+                        start,
+                        format!("Whitespace at the end of `{directive}'"),
+                        true,
+                    );
+                }
+                let fixed_list = replace_in_label_list(label_list.trim_end(), |item| {
+                    let label = self.ref_label(item);
+                    if labels.contains_key(&label) {
+                        return None;
+                    }
+                    match self.label_candidates(&label, labels)[..] {
+                        [candidate] => {
+                            problem(
+                                start,
+                                format!("Reference `{label}' to label `{candidate}'"),
+                                true,
+                            );
+                            Some(escape(candidate))
+                        }
+                        [] => {
+                            problem(start, format!("Unknown reference `{label}'"), false);
+                            None
+                        }
+                        ref candidates => {
+                            problem(
+                                start,
+                                format!(
+                                    "Ambiguous reference `{label}', which may refer to `{}'",
+                                    candidates.join("', `")
+                                ),
+                                false,
+                            );
+                            None
+                        }
+                    }
+                });
+                let fixed = if spacing {
+                    format!(
+                        "{{{{{}{reftype}: {}}}}}",
+                        self.namespace(),
+                        fixed_list.trim_start()
+                    )
+                } else {
+                    let label_start = caps.name("label").unwrap().start() - whole.start();
+                    format!("{}{fixed_list}}}}}", &directive[..label_start])
+                };
+                if fixed != directive {
+                    edits.push((start..start + directive.len(), fixed));
+                }
+            }
+            offset += segment.len();
+        }
+        problems.sort_by_key(|problem| (problem.line, problem.column));
+        (problems, edits)
+    }
+
+    /// Renames label `old` to `new` in all chapter source files of the book located in `book_dir`
+    /// and returns the list of modified files together with the number of renamed occurrences in each of them;
+    /// files are only written if `write` is true.
+    /// Fails without modifying anything if label `new` is already defined.
+    pub fn rename_label_in_book(
+        book_dir: &Path,
+        old: &str,
+        new: &str,
+        write: bool,
+    ) -> Result<Vec<(PathBuf, usize)>> {
+        let md = MDBook::load(book_dir)?;
+        let pre = Self::from_config(&md.config);
+        let src_dir = md.root.join(&md.config.book.src);
+
+        let mut files = Vec::new();
+        for item in md.book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(source_path) = &chapter.source_path {
+                    let file_path = src_dir.join(source_path);
+                    let content = std::fs::read_to_string(&file_path)?;
+                    if pre.defines_label(&content, new) {
+                        return Err(mdbook::errors::Error::msg(format!(
+                            "Label `{new}' is already defined in {}",
+                            file_path.display()
+                        )));
+                    }
+                    files.push((file_path, content));
+                }
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (file_path, content) in files {
+            let (output, count) = pre.rename_label(&content, old, new);
+            if count > 0 {
+                if write {
+                    std::fs::write(&file_path, output)?;
+                }
+                changes.push((file_path, count));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Checks the label definitions and references of all chapter source files of the book located in `book_dir`
+    /// and returns the files with problems: unknown, ambiguous, or misspelled references, labels defined several
+    /// times, and whitespace or spacing problems in directives.
+    /// Mechanical problems are fixed in the returned content of the files, which are only written if `write` is true;
+    /// ambiguous problems are left alone.
+    pub fn check_book(book_dir: &Path, write: bool) -> Result<Vec<CheckedFile>> {
+        let md = MDBook::load(book_dir)?;
+        let pre = Self::from_config(&md.config);
+        let src_dir = md.root.join(&md.config.book.src);
+
+        let mut files = Vec::new();
+        for item in md.book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(source_path) = &chapter.source_path {
+                    let file_path = src_dir.join(source_path);
+                    let content = std::fs::read_to_string(&file_path)?;
+                    files.push((file_path, content));
+                }
+            }
+        }
+
+        // the first definition of each label, indexed as in references
+        let mut labels: HashMap<String, (PathBuf, usize, String)> = HashMap::new();
+        for (file_path, content) in &files {
+            for (label, source, offset) in pre.defined_labels(content) {
+                labels
+                    .entry(label)
+                    .or_insert_with(|| (file_path.clone(), offset, source));
+            }
+        }
+
+        let mut checked = Vec::new();
+        for (file_path, content) in files {
+            let (problems, edits) = pre.check_content(&content, &file_path, &labels);
+            if problems.is_empty() {
+                continue;
+            }
+            let (fixed, diff) = apply_edits(&content, edits);
+            if let (true, Some(fixed)) = (write, &fixed) {
+                std::fs::write(&file_path, fixed)?;
+            }
+            checked.push(CheckedFile {
+                path: file_path,
+                problems,
+                fixed,
+                diff,
+            });
+        }
+        Ok(checked)
+    }
+}
+
+impl Default for NumThmPreprocessor {
+    fn default() -> Self {
+        let thm = Env::new("thm", "Theorem", "**");
+        let lem = Env::new("lem", "Lemma", "**");
+        let prop = Env::new("prop", "Proposition", "**");
+        let def = Env::new("def", "Definition", "**");
+        let rem = Env::new("rem", "Remark", "*");
+        let example = Env::new("example", "Example", "*");
+        let cor = Env::new("cor", "Corollary", "**");
+        let exer = Env::new("exer", "Exercise", "*");
+        // standing assumptions are numbered (H1), (H2), etc. throughout the book
+        let hyp = Env {
+            template: Some("(H{n})".to_string()),
+            reset: Some(Reset::Never),
+            ..Env::new("hyp", "Hypothesis", "**")
+        };
+        // equations are only tagged with their number, e.g. "(1.7)"
+        let eq = Env {
+            header_template: Some(EQ_TAG.to_string()),
+            ..Env::new("eq", "Equation", "")
+        };
+        let envs = vec![thm, lem, prop, def, rem, example, cor, exer, hyp, eq];
+        let enabled_reftypes: Vec<String> = REFTYPES.iter().map(|r| r.to_string()).collect();
+        let proof_lead_words = vec!["Proof".to_string()];
+        let regexes = Regexes::new(
+            &envs,
+            "",
+            DirectiveSyntax::Brackets,
+            &enabled_reftypes,
+            &proof_lead_words,
+        );
+
+        Self {
+            envs,
+            regexes,
+            with_prefix: false,
+            heading_depth: None,
+            unnumbered_chapters: UnnumberedChapters::Empty,
+            appendix_prefix: false,
+            all_unnumbered: false,
+            prefix_from_filename: false,
+            prefix_overrides: HashMap::new(),
+            reset: Reset::Chapter,
+            prefix_depth: None,
+            number_offset: 0,
+            numbering_style: NumberingStyle::Arabic,
+            appendix_paths: Vec::new(),
+            only_paths: Vec::new(),
+            appendix_part: None,
+            highlight_target: false,
+            anchor_attribute: AnchorAttribute::Id,
+            leading_newline: false,
+            print_pagerefs: false,
+            citation_mode: false,
+            absorb_trailing_punct: false,
+            ref_location_tooltip: false,
+            todo_unknown_refs: false,
+            missing_ref_text: "**[??]**".to_string(),
+            ref_template: None,
+            enabled_reftypes,
+            ref_join_separator: ", ".to_string(),
+            ref_join_last: " and ".to_string(),
+            ref_oxford_comma: false,
+            starred_envs: true,
+            strict: false,
+            name_separator: " ".to_string(),
+            separator: ".".to_string(),
+            prefix_sep: ".".to_string(),
+            header_punct: ".".to_string(),
+            html_emphasis: false,
+            clipboard_numbers: false,
+            margin_tags: false,
+            emit_classes: false,
+            renderers: vec!["html".to_string()],
+            html_renderer: true,
+            namespace: None,
+            directive_syntax: DirectiveSyntax::Brackets,
+            detect_proofs: false,
+            collapsible_proof: true,
+            link_proofs: false,
+            proof_lead_words,
+            index_sort: IndexSort::Appearance,
+            label_lint: None,
+            global_unique_anchors: false,
+            label_normalization: Vec::new(),
+            counter_import: None,
+            counter_export: None,
+            config_errors: Vec::new(),
+            export_labels: None,
+        }
+    }
+}
+
+impl Preprocessor for NumThmPreprocessor {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    // mdbook only asks the preprocessor when `renderers` is not configured, so that the default applies
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        self.renderers.iter().any(|r| r == renderer)
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        if !self.config_errors.is_empty() {
+            return Err(Error::msg(self.config_errors.join("\n")));
+        }
+
+        // raw HTML elements are only emitted for the HTML renderer
+        if self.html_renderer != (ctx.renderer == "html") {
+            let pre = Self {
+                html_renderer: ctx.renderer == "html",
+                ..self.clone()
+            };
+            return pre.run(ctx, book);
+        }
+
+        // a hashmap mapping labels to `LabelInfo` structs
+        let mut refs: HashMap<String, LabelInfo> = HashMap::new();
+        // a hashmap mapping counter names (by default, environment keys) to counters,
+        // possibly continuing the counters of a previous build
+        let mut counters: HashMap<String, usize> = match &self.counter_import {
+            Some(import_path) => {
+                let import_path = ctx.root.join(import_path);
+                match std::fs::read_to_string(&import_path) {
+                    Ok(import) => serde_json::from_str(&import)?,
+                    // e.g. the first build of a book sharing its import and export paths
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        warn!(
+                            "Counter import file {} not found, counters start from zero",
+                            import_path.display()
+                        );
+                        HashMap::new()
+                    }
+                    Err(e) => {
+                        return Err(Error::msg(format!(
+                            "Cannot import counters from {}: {e}",
+                            import_path.display()
+                        )))
+                    }
+                }
+            }
+            None => HashMap::new(),
+        };
+        // positions of label definitions and references, for the label export
+        let mut definitions = Vec::new();
+        let mut references = Vec::new();
+        // chapters modified by the first pass
+        let mut modified: HashSet<PathBuf> = HashSet::new();
+        // the chapters defining each label, if labels must be unique across the book
+        let mut label_chapters: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        // the chapters matched by `appendix_paths`
+        let appendix_paths: Vec<Regex> = self
+            .appendix_paths
+            .iter()
+            .map(|pattern| glob_regex(pattern))
+            .collect();
+        let appendix_marker = format!("{{{{{}{APPENDIX}}}}}", self.namespace());
+        // the chapters matched by `only_paths`, the other chapters being excluded
+        let only_paths: Vec<Regex> = self
+            .only_paths
+            .iter()
+            .map(|pattern| glob_regex(pattern))
+            .collect();
+        let mut excluded: HashSet<PathBuf> = HashSet::new();
+        // whether the appendix marker or the appendix part title has been found before
+        let mut appendix_started = false;
+        // the top-level section numbers of appendix chapters (`None` for unnumbered ones),
+        // the position of a number determining the letter
+        let mut appendix_numbers: Vec<Option<u32>> = Vec::new();
+        // whether a numbered chapter has been found before, unnumbered chapters being then suffix chapters
+        let mut numbered_started = false;
+
+        // whether a part title has been found since the last chapter (or the book starts)
+        let mut new_part = true;
+        // the number of the last chapter, truncated to the prefix depth
+        let mut last_number = None;
+        // the paths of `prefix_overrides` which have been found in the book
+        let mut seen_overrides: HashSet<String> = HashSet::new();
+        // the unnumbered chapters containing environments, reported once for the whole book
+        let mut unnumbered_paths: Vec<String> = Vec::new();
+        // the path of each chapter with the counters which were not reset at its start and at its end
+        let mut chapter_counters: Vec<(PathBuf, HashSet<String>, HashMap<String, usize>)> =
+            Vec::new();
+        let depth = self.prefix_depth.unwrap_or(usize::MAX);
+
+        // chapters are processed in the order of the summary, which matters for counters running through the book
+        for_each_mut_in_order(&mut book.sections, &mut |item: &mut BookItem| {
+            if let BookItem::PartTitle(title) = item {
+                new_part = true;
+                appendix_started |= self.appendix_part.as_ref() == Some(title);
+            }
+            if let BookItem::Chapter(chapter) = item {
+                if !chapter.is_draft_chapter() {
+                    // one can safely unwrap chapter.path which must be Some(...)
+                    let path = chapter.path.as_ref().unwrap();
+                    let original = chapter.content.clone();
+                    // the appendix marker applies to the current chapter and all following ones
+                    let with_marker = split_untouched_regions(&chapter.content).into_iter().any(
+                        |(untouched, segment)| !untouched && segment.contains(&appendix_marker),
+                    );
+                    if with_marker {
+                        chapter.content =
+                            replace_outside_code_regions(&chapter.content, |segment| {
+                                segment.replace(&appendix_marker, "")
+                            });
+                    }
+                    let path_str = path.to_string_lossy().replace('\\', "/");
+                    let appendix = appendix_started
+                        || with_marker
+                        || appendix_paths.iter().any(|re| re.is_match(&path_str));
+                    appendix_started |= with_marker;
+                    let number = match &chapter.number {
+                        Some(sn) if appendix && !sn.is_empty() => {
+                            // counters reset per chapter restart in each appendix (see below),
+                            // the others run through the appendices according to their policy
+                            if !appendix_numbers.contains(&Some(sn[0])) {
+                                appendix_numbers.push(Some(sn[0]));
+                            }
+                            let i = appendix_numbers
+                                .iter()
+                                .position(|n| *n == Some(sn[0]))
+                                .unwrap();
+                            let mut number = appendix_letter(i);
+                            for n in sn[1..].iter().take(depth - 1) {
+                                number.push_str(&format!("{}{n}", self.separator));
+                            }
+                            number.push_str(&self.separator);
+                            Some(number)
+                        }
+                        Some(sn) => Some(truncate_section_number(sn, depth, &self.separator)),
+                        // unnumbered appendices and suffix chapters get the next letter
+                        None if self.appendix_prefix && (appendix || numbered_started) => {
+                            let letter = appendix_letter(appendix_numbers.len());
+                            appendix_numbers.push(None);
+                            Some(format!("{letter}{}", self.separator))
+                        }
+                        None => None,
+                    };
+                    numbered_started |= chapter.number.is_some();
+                    // with a prefix depth, chapters sharing the same truncated number share their counters
+                    let same_number =
+                        self.prefix_depth.is_some() && number.is_some() && number == last_number;
+                    // counters are reset according to the policy of their environment
+                    for env in &self.envs {
+                        let reset = match env.reset.unwrap_or(self.reset) {
+                            Reset::Chapter => !same_number,
+                            Reset::Part => new_part,
+                            Reset::Never => false,
+                        };
+                        if reset {
+                            counters.remove(env.counter_name());
+                        }
+                    }
+                    new_part = false;
+                    last_number.clone_from(&number);
+                    // chapters with an overridden prefix have their own counters
+                    let prefix_override = self.prefix_overrides.get(&path_str);
+                    let mut own_counters = HashMap::new();
+                    let counters = match prefix_override {
+                        Some(_) => {
+                            seen_overrides.insert(path_str.clone());
+                            &mut own_counters
+                        }
+                        None => &mut counters,
+                    };
+                    // sub-numbered environments and environments numbered within another one only attach to
+                    // an environment of the same chapter
+                    counters.retain(|counter, _| {
+                        !counter.ends_with(SUBNUMBER) && !counter.ends_with(PARENT)
+                    });
+                    let continued: HashSet<String> = counters.keys().cloned().collect();
+                    let unnumbered = self.with_prefix
+                        && number.is_none()
+                        && prefix_override.is_none()
+                        && !self.prefix_from_filename;
+                    if unnumbered
+                        && self
+                            .envs
+                            .iter()
+                            .any(|env| env.regex.is_match(&chapter.content))
+                    {
+                        unnumbered_paths.push(path.display().to_string());
+                    }
+                    let prefix = if let Some(prefix) = prefix_override {
+                        prefix.clone()
+                    } else if self.prefix_from_filename {
+                        filename_prefix(path, &self.separator)
+                    } else if self.with_prefix {
+                        match (number, &self.unnumbered_chapters) {
+                            (Some(number), _) => number,
+                            (None, UnnumberedChapters::Stem) => {
+                                filename_prefix(path, &self.separator)
+                            }
+                            (None, UnnumberedChapters::Fixed(prefix)) => prefix.clone(),
+                            (None, _) => String::new(),
+                        }
+                    } else {
+                        String::new()
+                    };
+                    // environments of unnumbered chapters may be processed as starred variants
+                    let unnumbered_pre;
+                    let pre = if unnumbered
+                        && self.unnumbered_chapters == UnnumberedChapters::Unnumbered
+                    {
+                        unnumbered_pre = Self {
+                            all_unnumbered: true,
+                            ..self.clone()
+                        };
+                        &unnumbered_pre
+                    } else {
+                        self
+                    };
+                    for warning in self.lint_label_prefixes(&chapter.content, path) {
+                        warn!("{warning}");
+                    }
+                    if self.global_unique_anchors {
+                        for env in &self.envs {
+                            for (label, _) in find_labels(&env.regex, &chapter.content) {
+                                let label = normalize_label(&label, &self.label_normalization);
+                                let chapters = label_chapters.entry(label).or_default();
+                                if !chapters.contains(path) {
+                                    chapters.push(path.clone());
+                                }
+                            }
+                        }
+                    }
+                    if self.export_labels.is_some() {
+                        self.find_label_positions(
+                            &chapter.content,
+                            path,
+                            &mut definitions,
+                            &mut references,
+                        );
+                    }
+                    let registered = refs.len();
+                    // deferred proofs are located first, so that the headers of their environments link them
+                    let (content, deferred) = pre.locate_deferred_proofs(&chapter.content);
+                    let mut links = ProofLinks {
+                        deferred,
+                        ..Default::default()
+                    };
+                    chapter.content = match self.heading_depth {
+                        Some(depth) => pre.find_and_replace_envs_by_heading(
+                            &content, &prefix, depth, path, counters, &mut refs, &mut links,
+                        ),
+                        None => pre.find_and_replace_all_envs(
+                            &content, &prefix, path, &self.envs, counters, &mut refs, &mut links,
+                        ),
+                    };
+                    if counters.remove(SUBEQ_OPEN).is_some() {
+                        warn!("{}: Unterminated group of sub-equations", path.display());
+                    }
+                    // labels registered by this chapter record its section number
+                    if let Some(sn) = &chapter.number {
+                        for info in refs.values_mut().filter(|info| info.index >= registered) {
+                            info.section.clone_from(sn);
+                        }
+                    }
+                    chapter_counters.push((path.clone(), continued, counters.clone()));
+                    // some renderers collapse an anchor located at the very start of a file
+                    if self.leading_newline && chapter.content.starts_with("<a ") {
+                        chapter.content.insert(0, '\n');
+                    }
+                    // environments of excluded chapters are numbered, but their content is left as is
+                    if !only_paths.is_empty() && !only_paths.iter().any(|re| re.is_match(&path_str))
+                    {
+                        for info in refs.values_mut().filter(|info| info.index >= registered) {
+                            info.excluded = true;
+                        }
+                        chapter.content.clone_from(&original);
+                        excluded.insert(path.clone());
+                    }
+                    if chapter.content != original {
+                        modified.insert(path.clone());
+                    }
+                }
+            }
+        });
+
+        for path in self.prefix_overrides.keys() {
+            if !seen_overrides.contains(path) {
+                warn!("Prefix override for unknown chapter `{path}'");
+            }
+        }
+
+        if !unnumbered_paths.is_empty() {
+            let numbering = match &self.unnumbered_chapters {
+                UnnumberedChapters::Empty => "numbered without prefix".to_string(),
+                UnnumberedChapters::Stem => "prefixed by their file stem".to_string(),
+                UnnumberedChapters::Fixed(prefix) => format!("prefixed by `{prefix}'"),
+                UnnumberedChapters::Unnumbered => "not numbered".to_string(),
+            };
+            warn!(
+                "Environments of unnumbered chapters are {numbering} (see the `unnumbered_chapters' option): {}",
+                unnumbered_paths.join(", ")
+            );
+        }
+
+        let mut collisions: Vec<String> = label_chapters
+            .into_iter()
+            .filter(|(_, chapters)| chapters.len() > 1)
+            .map(|(label, chapters)| {
+                let chapters: Vec<_> = chapters.iter().map(|c| c.display().to_string()).collect();
+                format!(
+                    "Label `{label}' defined in several chapters: {}",
+                    chapters.join(", ")
+                )
+            })
+            .collect();
+        if !collisions.is_empty() {
+            collisions.sort();
+            return Err(Error::msg(collisions.join("\n")));
+        }
+
+        if self.strict {
+            let mut duplicates: Vec<String> = refs
+                .iter()
+                .filter(|(_, info)| !info.redefinitions.is_empty())
+                .map(|(label, info)| {
+                    let redefinitions: Vec<_> = info
+                        .redefinitions
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect();
+                    format!(
+                        "Label `{label}' defined in {} and redefined in {}",
+                        info.path.display(),
+                        redefinitions.join(", ")
+                    )
+                })
+                .collect();
+            for (label, info) in &refs {
+                for collision in &info.collisions {
+                    duplicates.push(format!(
+                        "Labels `{}' and `{collision}' are both normalized to `{label}'",
+                        info.source
+                    ));
+                }
+            }
+            if !duplicates.is_empty() {
+                duplicates.sort();
+                return Err(Error::msg(duplicates.join("\n")));
+            }
+        }
+
+        // the total of each counter in each chapter is its value at the end of the last chapter
+        // before the counter is reset
+        let mut totals: HashMap<PathBuf, HashMap<String, usize>> = HashMap::new();
+        let mut next_totals: HashMap<String, usize> = HashMap::new();
+        for (path, continued, end_counters) in chapter_counters.into_iter().rev() {
+            let chapter_totals: HashMap<String, usize> = end_counters
+                .into_iter()
+                .map(|(counter, value)| {
+                    let total = next_totals.get(&counter).copied().unwrap_or(value);
+                    (counter, total)
+                })
+                .collect();
+            next_totals = chapter_totals
+                .iter()
+                .filter(|(counter, _)| continued.contains(*counter))
+                .map(|(counter, total)| (counter.clone(), *total))
+                .collect();
+            totals.insert(path, chapter_totals);
+        }
+
+        // counters are not exported when processing already processed chapters
+        if let (Some(export_path), false) = (&self.counter_export, modified.is_empty()) {
+            let export: BTreeMap<&String, &usize> = counters
+                .iter()
+                .filter(|(counter, _)| !counter.ends_with(SUBNUMBER) && !counter.ends_with(PARENT))
+                .collect();
+            std::fs::write(
+                ctx.root.join(export_path),
+                serde_json::to_string_pretty(&export)?,
+            )?;
+        }
+
+        // nothing is exported when processing already processed chapters
+        let nothing_to_export = definitions.is_empty() && references.is_empty();
+        if let (Some(export_path), false) = (&self.export_labels, nothing_to_export) {
+            let export = export_labels(&definitions, &references, &refs);
+            std::fs::write(
+                ctx.root.join(export_path),
+                serde_json::to_string_pretty(&export)?,
+            )?;
+        }
+
+        // the unknown references of all chapters, reported in strict mode
+        let mut unresolved: Vec<String> = Vec::new();
+
+        book.for_each_mut(|item: &mut BookItem| {
+            if let BookItem::Chapter(chapter) = item {
+                if !chapter.is_draft_chapter() && !excluded.contains(chapter.path.as_ref().unwrap())
+                {
+                    // one can safely unwrap chapter.path which must be Some(...)
+                    let path = chapter.path.as_ref().unwrap();
+                    if self.envs.iter().any(|env| env.show_total) {
+                        chapter.content = fill_totals(&chapter.content, &totals[path]);
+                    }
+                    if self.detect_proofs {
+                        chapter.content = self.format_proofs(&chapter.content, path, &refs);
+                    }
+                    chapter.content =
+                        self.find_and_replace_block_envs(&chapter.content, path, &refs);
+                    let mut unknown = Vec::new();
+                    chapter.content =
+                        self.find_and_replace_all_refs(&chapter.content, path, &refs, &mut unknown);
+                    for label in unknown {
+                        unresolved.push(format!("{}: Unknown reference `{label}'", path.display()));
+                    }
+                    chapter.content = self.find_and_replace_registry(&chapter.content, path, &refs);
+                    chapter.content = self.find_and_replace_listof(&chapter.content, path, &refs);
+                    let unescaped = strip_escapes(&chapter.content);
+                    // unescaped directives would be processed if the output is processed again,
+                    // so the output is then marked to be left untouched (with a comment, which is
+                    // only invisible in HTML)
+                    chapter.content = if unescaped != chapter.content && self.html_renderer {
+                        format!("{PROCESSED_START}\n{unescaped}\n{PROCESSED_END}")
+                    } else {
+                        unescaped
+                    };
+                }
+            }
+        });
+
+        if self.strict && !unresolved.is_empty() {
+            return Err(Error::msg(unresolved.join("\n")));
+        }
+
+        Ok(book)
+    }
+}
+
+impl NumThmPreprocessor {
+    /// Same as `find_and_replace_all_envs` for the single environment `env`.
+    #[cfg(test)]
+    fn find_and_replace_envs(
+        &self,
+        s: &str,
+        prefix: &str,
+        path: &Path,
+        env: &Env,
+        counters: &mut HashMap<String, usize>,
+        refs: &mut HashMap<String, LabelInfo>,
+    ) -> String {
+        self.find_and_replace_all_envs(
+            s,
+            prefix,
+            path,
+            std::slice::from_ref(env),
+            counters,
+            refs,
+            &mut ProofLinks::default(),
+        )
+    }
+
+    /// Finds all patterns `{{key}}{mylabel}[mytitle]` where `key` is the key field of an environment of `envs`
+    /// (which is either the list of all environments or a single environment) (e.g. `thm`) and replaces them with a header (including the title if a title `mytitle` is provided)
+    /// and potentially an anchor if a label `mylabel` is provided;
+    /// if a label is provided, it updates the hashmap `refs` with an entry (label, LabelInfo)
+    /// allowing to format links to the theorem.
+    /// Patterns are processed in document order, the counters (indexed by counter name) being read from
+    /// and written back to the hashmap `counters`, so that environments may share a counter.
+    /// For environments with the quote style, the statement following the pattern is also quoted.
+    /// Proof blocks are linked to the environments preceding them according to `links`.
+    #[allow(clippy::too_many_arguments)]
+    fn find_and_replace_all_envs(
+        &self,
+        s: &str,
+        prefix: &str,
+        path: &Path,
+        envs: &[Env],
+        counters: &mut HashMap<String, usize>,
+        refs: &mut HashMap<String, LabelInfo>,
+        links: &mut ProofLinks,
+    ) -> String {
+        let set_re = &self.regexes.set;
+        // a single regex matching all environments, so that they are processed in document order
+        let re = match envs {
+            [env] => &env.regex,
+            _ => &self.regexes.envs,
+        };
+        replace_outside_code_regions(s, |segment| {
+            // counters are set (and groups of sub-equations are opened or closed) between the environments
+            // preceding and following the directive, which is removed from the output
+            let mut output = String::new();
+            let mut pos = 0;
+            let mut directives: Vec<regex::Captures> = set_re
+                .captures_iter(segment)
+                .chain(self.regexes.subeq_begin.captures_iter(segment))
+                .chain(self.regexes.subeq_end.captures_iter(segment))
+                .collect();
+            directives.sort_by_key(|caps| caps.get(0).unwrap().start());
+            for caps in directives {
+                let directive = caps.get(0).unwrap();
+                output.push_str(&self.replace_envs(
+                    &segment[pos..directive.start()],
+                    re,
+                    prefix,
+                    path,
+                    envs,
+                    counters,
+                    refs,
+                    links,
+                ));
+                if let Some(assignments) = caps.name("assignments") {
+                    self.set_counters(assignments.as_str(), path, counters);
+                } else if caps.name("key").is_some() {
+                    output.push_str(&self.begin_subequations(&caps, prefix, path, counters, refs));
+                } else if counters.remove(SUBEQ_OPEN).is_none() {
+                    warn!(
+                        "{}: `{}' without a preceding `{{{{{}{SUBEQUATIONS}-begin}}}}', ignoring it",
+                        path.display(),
+                        directive.as_str(),
+                        self.namespace()
+                    );
+                }
+                pos = directive.end();
+            }
+            output.push_str(&self.replace_envs(
+                &segment[pos..],
+                re,
+                prefix,
+                path,
+                envs,
+                counters,
+                refs,
+                links,
+            ));
+            output
+        })
+    }
+
+    /// Same as `find_and_replace_all_envs` with all environments, `prefix` being extended by the numbers of
+    /// the headings of levels 2 to `depth` preceding each environment, e.g. "4.3." after the third `##` heading
+    /// of chapter 4, and counters reset at each chapter being reset at each of these headings.
+    #[allow(clippy::too_many_arguments)]
+    fn find_and_replace_envs_by_heading(
+        &self,
+        s: &str,
+        prefix: &str,
+        depth: usize,
+        path: &Path,
+        counters: &mut HashMap<String, usize>,
+        refs: &mut HashMap<String, LabelInfo>,
+        links: &mut ProofLinks,
+    ) -> String {
+        let mut output = String::new();
+        // the numbers of the current headings of levels 2 to `depth`
+        let mut numbers = vec![0; depth - 1];
+        let mut start = 0;
+        let headings = find_headings(s, depth);
+        for (end, level) in headings.into_iter().chain([(s.len(), 0)]) {
+            // the numbers of missing headings, e.g. before the first heading, are omitted
+            let last = numbers.iter().rposition(|n| *n > 0).map_or(0, |i| i + 1);
+            let mut heading_prefix = prefix.to_string();
+            for n in &numbers[..last] {
+                heading_prefix.push_str(&format!("{n}{}", self.separator));
+            }
+            output.push_str(&self.find_and_replace_all_envs(
+                &s[start..end],
+                &heading_prefix,
+                path,
+                &self.envs,
+                counters,
+                refs,
+                links,
+            ));
+            if level >= 2 {
+                numbers[level - 2] += 1;
+                numbers[level - 1..].iter_mut().for_each(|n| *n = 0);
+                for env in &self.envs {
+                    if env.reset.unwrap_or(self.reset) == Reset::Chapter {
+                        counters.remove(env.counter_name());
+                    }
+                }
+            }
+            start = end;
+        }
+        output
+    }
+
+    /// Opens the group of sub-equations started by the directive matched by `caps`, which is numbered
+    /// as an equation whose number is shared by the equations of the group, followed by a letter,
+    /// and returns the anchor of the group if it is labeled.
+    fn begin_subequations(
+        &self,
+        caps: &regex::Captures,
+        prefix: &str,
+        path: &Path,
+        counters: &mut HashMap<String, usize>,
+        refs: &mut HashMap<String, LabelInfo>,
+    ) -> String {
+        let directive = &caps[0];
+        let eq = match self.envs.iter().find(|env| env.key == "eq") {
+            Some(eq) if !counters.contains_key(SUBEQ_OPEN) => eq,
+            Some(_) => {
+                warn!(
+                    "{}: Nested `{directive}' in a group of sub-equations, ignoring it",
+                    path.display()
+                );
+                return String::new();
+            }
+            None => {
+                warn!(
+                    "{}: `{directive}' without an `eq' environment, ignoring it",
+                    path.display()
+                );
+                return String::new();
+            }
+        };
+        // the header of the group is dropped, but its label is registered
+        self.env_header(caps, None, prefix, path, eq, counters, refs);
+        counters.insert(SUBEQ_OPEN.to_string(), 0);
+        match caps.name("label") {
+            Some(label) => {
+                let label = normalize_label(&unescape(label.as_str()), &self.label_normalization);
+                format!("<a {}></a>", self.anchor_attribute.attrs(&anchor(&label)))
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Sets counters according to `assignments` of the form `key1=value1 key2=value2 ...`,
+    /// where each key is the key of an environment or the name of a counter.
+    fn set_counters(&self, assignments: &str, path: &Path, counters: &mut HashMap<String, usize>) {
+        for assignment in assignments.split_whitespace() {
+            let (key, value) = match assignment.split_once('=') {
+                Some(assignment) => assignment,
+                None => {
+                    warn!(
+                        "{}: Invalid counter assignment `{assignment}'",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            let counter = match self.envs.iter().find(|env| env.key == key) {
+                Some(env) => env.counter_name(),
+                None if self.envs.iter().any(|env| env.counter_name() == key) => key,
+                None => {
+                    warn!("{}: Unknown counter `{key}'", path.display());
+                    continue;
+                }
+            };
+            match value.parse::<usize>() {
+                Ok(value) => {
+                    counters.insert(counter.to_string(), value);
+                }
+                Err(_) => warn!(
+                    "{}: Invalid value `{value}' for counter `{key}', which must be a non-negative integer",
+                    path.display()
+                ),
+            }
+        }
+    }
+
+    /// Replaces all environments of `envs` in `segment` (which contains no skip or code region),
+    /// matched by the combined regex `re` of all environments, in document order, updating `counters`, `refs`
+    /// and the environment preceding the proof blocks of the chapter in `links`.
+    #[allow(clippy::too_many_arguments)]
+    fn replace_envs(
+        &self,
+        segment: &str,
+        re: &Regex,
+        prefix: &str,
+        path: &Path,
+        envs: &[Env],
+        counters: &mut HashMap<String, usize>,
+        refs: &mut HashMap<String, LabelInfo>,
+        links: &mut ProofLinks,
+    ) -> String {
+        let mut output = String::new();
+        let mut pos = 0;
+        while let Some(caps) = re.captures_at(segment, pos) {
+            let env = envs.iter().find(|env| env.key == caps["key"]).unwrap();
+            let whole = caps.get(0).unwrap();
+            output.push_str(&self.link_proof_blocks(&segment[pos..whole.start()], path, links));
+            if caps.name("star").is_some() && !self.starred_envs {
+                // starred variants are left as is when disabled
+                output.push_str(whole.as_str());
+                pos = whole.end();
+                continue;
+            }
+            let line = match env.style {
+                Style::Plain if env.capture_line => {
+                    let rest = &segment[whole.end()..];
+                    Some(&rest[..rest.find('\n').unwrap_or(rest.len())])
+                }
+                _ => None,
+            };
+            let (anchor, header, num_name) =
+                self.env_header(&caps, line, prefix, path, env, counters, refs);
+            let label = caps.name("label").map(|l| l.as_str());
+            // equations are not proved
+            if env.key != "eq" {
+                links.preceding =
+                    Some(num_name.map(|num_name| (num_name, label.map(String::from))));
+            }
+            // deferred proofs are linked from the header
+            let header = match label
+                .map(|l| normalize_label(&unescape(l), &self.label_normalization))
+                .and_then(|label| links.deferred.get(&label))
+            {
+                Some(proof) => format!("{header} [[Proof ↓]](#{proof})"),
+                None => header,
+            };
+            if let Some(anchor) = anchor {
+                output.push_str(&anchor);
+                output.push('\n');
+            }
+            pos = whole.end() + line.map_or(0, str::len);
+            match env.style {
+                Style::Plain => output.push_str(&header),
+                Style::Quote => {
+                    let end_marker = format!("{{{{{}{}-end}}}}", self.namespace(), env.key);
+                    let (statement, end) =
+                        quoted_statement(&segment[pos..], &env.regex, &end_marker);
+                    // patterns in the statement are processed before being quoted
+                    let statement = self.find_and_replace_all_envs(
+                        statement, prefix, path, envs, counters, refs, links,
+                    );
+                    output.push_str(&quote(&format!("{header}{statement}")));
+                    pos += end;
+                }
+            }
+        }
+        output.push_str(&self.link_proof_blocks(&segment[pos..], path, links));
+        output
+    }
+
+    /// Gives the title "of label" (or "of Theorem N" for an unlabeled environment) to the first untitled proof block
+    /// following a numbered environment if `link_proofs` is set, `s` being a part of the chapter containing no
+    /// environment, and warns about untitled proof blocks which do not follow any environment of the chapter.
+    fn link_proof_blocks(&self, s: &str, path: &Path, links: &mut ProofLinks) -> String {
+        if !self.link_proofs {
+            return s.to_string();
+        }
+        let mut output = String::new();
+        let mut pos = 0;
+        for caps in self.regexes.proof.captures_iter(s) {
+            let whole = caps.get(0).unwrap();
+            output.push_str(&s[pos..whole.start()]);
+            pos = whole.start();
+            if caps.name("title").is_some() {
+                links.preceding = Some(None);
+                continue;
+            }
+            let target = match &mut links.preceding {
+                Some(preceding) => preceding.take(),
+                None => {
+                    warn!("{}: Proof without a preceding environment", path.display());
+                    continue;
+                }
+            };
+            let target = match target {
+                Some((num_name, label)) => label.unwrap_or(num_name),
+                None => continue,
+            };
+            // the title follows the label of the proof, if any
+            let end = whole.start() + whole.as_str().find("}}").unwrap();
+            let (title_pos, title) = match (self.directive_syntax, caps.name("label")) {
+                (DirectiveSyntax::Brackets, Some(l)) => (l.end() + 1, format!("[of {target}]")),
+                (DirectiveSyntax::Brackets, None) => (end + 2, format!("[of {target}]")),
+                (DirectiveSyntax::Pipes, Some(_)) => (end, format!(" | of {target}")),
+                (DirectiveSyntax::Pipes, None) => (end, format!(" | | of {target}")),
+            };
+            output.push_str(&s[pos..title_pos]);
+            output.push_str(&title);
+            pos = title_pos;
+        }
+        output.push_str(&s[pos..]);
+        output
+    }
+
+    /// Locates the deferred proof blocks of `s`, i.e. the proof blocks titled "of label" which do not follow
+    /// the environment with this label, if this environment lies in `s`, labeling them if they are unlabeled;
+    /// returns `s` with these labels and the anchors of the deferred proofs indexed by the label of their environment.
+    fn locate_deferred_proofs(&self, s: &str) -> (String, HashMap<String, String>) {
+        let mut deferred = HashMap::new();
+        if !self.link_proofs {
+            return (s.to_string(), deferred);
+        }
+        let untouched = untouched_ranges(s);
+        let touched = |m: &regex::Match| !untouched.iter().any(|r| r.contains(&m.start()));
+        let normalize = |label: &str| normalize_label(&unescape(label), &self.label_normalization);
+        // the positions and labels of the environments of `s`, except equations
+        let envs: Vec<(usize, Option<String>)> = self
+            .regexes
+            .envs
+            .captures_iter(s)
+            .filter(|caps| touched(&caps.get(0).unwrap()) && &caps["key"] != "eq")
+            .map(|caps| {
+                (
+                    caps.get(0).unwrap().start(),
+                    caps.name("label").map(|l| normalize(l.as_str())),
+                )
+            })
+            .collect();
+        let mut insertions = Vec::new();
+        for caps in self.regexes.proof.captures_iter(s) {
+            let whole = caps.get(0).unwrap();
+            let target = caps
+                .name("title")
+                .and_then(|t| Some(normalize(unescape(t.as_str()).strip_prefix("of ")?.trim())));
+            let target = match target {
+                Some(target) if touched(&whole) => target,
+                _ => continue,
+            };
+            let preceding = envs.iter().rev().find(|(pos, _)| *pos < whole.start());
+            if preceding.is_some_and(|(_, label)| label.as_ref() == Some(&target))
+                || !envs
+                    .iter()
+                    .any(|(_, label)| label.as_ref() == Some(&target))
+            {
+                continue;
+            }
+            let label = match caps.name("label") {
+                Some(label) => normalize(label.as_str()),
+                None => {
+                    let label = format!("proof-of-{target}");
+                    // the label is inserted in the empty label slot
+                    let (pos, inserted) = match self.directive_syntax {
+                        DirectiveSyntax::Brackets => (
+                            whole.start() + whole.as_str().find("}}").unwrap() + 2,
+                            format!("{{{}}}", escape(&label)),
+                        ),
+                        DirectiveSyntax::Pipes => (
+                            whole.start() + whole.as_str().find('|').unwrap() + 1,
+                            format!(" {}", escape(&label)),
+                        ),
+                    };
+                    insertions.push((pos, inserted));
+                    normalize(&escape(&label))
+                }
+            };
+            deferred.insert(target, anchor(&label));
+        }
+        let mut s = s.to_string();
+        for (pos, inserted) in insertions.into_iter().rev() {
+            s.insert_str(pos, &inserted);
+        }
+        (s, deferred)
+    }
+
+    /// Returns the number (without prefix) of the environment `env` matched by pattern `caps`, updating `counters`.
+    fn env_number(
+        &self,
+        caps: &regex::Captures,
+        path: &Path,
+        env: &Env,
+        counters: &mut HashMap<String, usize>,
+    ) -> String {
+        // equations of a group of sub-equations are sub-numbered
+        let sub =
+            caps.name("sub").is_some() || (env.key == "eq" && counters.contains_key(SUBEQ_OPEN));
+        // the number of sub-numbered environments attached to the last numbered one
+        let sub_counter = format!("{}{SUBNUMBER}", env.counter_name());
+        let letter = match (sub, counters.get_mut(&sub_counter)) {
+            (true, Some(sub)) => {
+                *sub += 1;
+                Some(format_counter(*sub, NumberingStyle::AlphaLower))
+            }
+            (true, None) => {
+                warn!(
+                    "{}: `{{{{{}{}+}}}}' without a preceding environment, numbering it normally",
+                    path.display(),
+                    self.namespace(),
+                    env.key
+                );
+                None
+            }
+            (false, _) => None,
+        };
+        if letter.is_none() {
+            counters.insert(sub_counter, 0);
+        }
+        let ctr = counters
+            .entry(env.counter_name().to_string())
+            .or_insert(env.start - 1 + self.number_offset);
+        if letter.is_none() {
+            *ctr += 1;
+        }
+        let ctr = *ctr;
+        let n =
+            env.format_counter(ctr, self.numbering_style) + letter.as_deref().unwrap_or_default();
+        let n = match &env.within {
+            Some(parent_key) => {
+                let parent = self.envs.iter().find(|e| &e.key == parent_key);
+                match (parent, counters.get(&format!("{parent_key}{PARENT}"))) {
+                    (Some(parent), Some(&value)) => {
+                        format!(
+                            "{}{}{n}",
+                            parent.format_counter(value, self.numbering_style),
+                            self.separator
+                        )
+                    }
+                    _ => {
+                        warn!(
+                            "{}: `{}' environment without a preceding `{parent_key}' environment, numbering it normally",
+                            path.display(),
+                            env.key
+                        );
+                        n
+                    }
+                }
+            }
+            None => n,
+        };
+        // environments numbered within this one restart with each of its (not sub-numbered) occurrences
+        let children: Vec<&Env> = self
+            .envs
+            .iter()
+            .filter(|e| e.within.as_ref() == Some(&env.key))
+            .collect();
+        if letter.is_none() && !children.is_empty() {
+            counters.insert(format!("{}{PARENT}", env.key), ctr);
+            for child in children {
+                counters.remove(child.counter_name());
+            }
+        }
+        n
+    }
+
+    /// Returns the anchor to be placed on its own line before the header, if any, the header
+    /// replacing pattern `caps` of environment `env` (followed by the captured `line`, if any),
+    /// and the numbered name of the environment if it is numbered, updating `counters` and `refs`.
+    #[allow(clippy::too_many_arguments)]
+    fn env_header(
+        &self,
+        caps: &regex::Captures,
+        line: Option<&str>,
+        prefix: &str,
+        path: &Path,
+        env: &Env,
+        counters: &mut HashMap<String, usize>,
+        refs: &mut HashMap<String, LabelInfo>,
+    ) -> (Option<String>, String, Option<String>) {
+        // prefixes end with the separator, which is replaced by the prefix separator
+        let prefix = match prefix.strip_suffix(self.separator.as_str()) {
+            Some(stripped) if !prefix.is_empty() => format!("{stripped}{}", self.prefix_sep),
+            _ => prefix.to_string(),
+        };
+        let prefix = prefix.as_str();
+        let attrs = caps
+            .name("attrs")
+            .map(|a| parse_attributes(a.as_str()))
+            .unwrap_or_default();
+        // a number fixed by attribute `number` is displayed without prefix and does not consume a number
+        let number = attrs
+            .iter()
+            .find(|(attr, _)| attr == "number")
+            .map(|(_, value)| value.clone())
+            .filter(|value| {
+                let valid = !value.is_empty() && !value.contains(char::is_whitespace);
+                if !valid {
+                    warn!(
+                        "{}: Invalid number `{value}', numbering it automatically",
+                        env.name
+                    );
+                }
+                valid
+            });
+        // starred environments are not numbered
+        let (n, prefix) = match (caps.name("star"), &number) {
+            (Some(_), _) => (None, prefix),
+            _ if self.all_unnumbered => (None, prefix),
+            (None, Some(number)) => (Some(number.clone()), ""),
+            (None, None) => (Some(self.env_number(caps, path, env, counters)), prefix),
+        };
+        let num_name = match &n {
+            Some(n) => env.num_name(prefix, n, &self.name_separator),
+            None => env.name.clone(),
+        };
+        let source = caps
+            .name("label")
+            .map(|l| unescape_label(l.as_str(), &num_name));
+        let label = source
+            .as_ref()
+            .map(|source| normalize_label(source, &self.label_normalization));
+        let title = caps.name("title").map(|t| unescape(t.as_str()));
+        let mut status = None;
+        let mut tags = Vec::new();
+        let mut emph = env.emph.clone();
+        for (attr, value) in attrs {
+            match attr.as_str() {
+                "emph" => emph = value,
+                "status" if STATUSES.iter().any(|(s, _)| *s == value) => status = Some(value),
+                "status" => warn!("{num_name}: Unknown status `{value}'"),
+                "tags" => tags = value.split(',').map(|tag| tag.trim().to_string()).collect(),
+                "number" => {}
+                _ => warn!("{num_name}: Unknown attribute `{attr}'"),
+            }
+        }
+        // references split their labels on commas, so such labels cannot be referenced,
+        if let Some(label) = label.as_ref().filter(|label| label.contains(',')) {
+            warn!("{num_name}: Label `{label}' contains a comma and cannot be referenced");
+        }
+        // and treat `--` as a range operator
+        if let Some(label) = label.as_ref().filter(|label| label.contains("--")) {
+            warn!("{num_name}: Label `{label}' contains `--' and cannot be referenced");
+        }
+        if let (Some(label), Some(source)) = (&label, source) {
+            // if a label is given, we must update the hashmap
+            if let Some(info) = refs.get_mut(label) {
+                if info.source != source {
+                    // distinct labels collide after normalization, so that their anchors are ambiguous
+                    warn!(
+                        "{num_name}: Labels `{}' and `{source}' are both normalized to `{label}'",
+                        info.source
+                    );
+                    info.collisions.push(source);
+                } else {
+                    // if the same label has already been used we emit a warning and only record the redefinition
+                    warn!("{num_name}: Label `{label}' already used");
+                    info.redefinitions.push(path.to_path_buf());
+                }
+            } else {
+                refs.insert(
+                    label.clone(),
+                    LabelInfo {
+                        key: env.key.clone(),
+                        num_name: num_name.clone(),
+                        num: n.as_ref().map(|n| env.num(prefix, n)).unwrap_or_default(),
+                        path: path.to_path_buf(),
+                        title: title.clone(),
+                        status: status.clone(),
+                        tags,
+                        index: refs.len(),
+                        redefinitions: Vec::new(),
+                        source,
+                        collisions: Vec::new(),
+                        section: Vec::new(),
+                        excluded: false,
+                    },
+                );
+            }
+        } else if status.is_some() {
+            warn!("{num_name}: Environments with a status must be labeled to be listed");
+        } else if !tags.is_empty() {
+            warn!("{num_name}: Environments with tags must be labeled to be listed");
+        }
+        let punct = &self.header_punct;
+        // the total is filled in by the second pass
+        let total = format!("{TOTAL_OPEN}{}{TOTAL_CLOSE}", env.counter_name());
+        let total_suffix = if env.show_total && n.is_some() && number.is_none() {
+            format!(" of {total}")
+        } else {
+            String::new()
+        };
+        let clipboard = |text: String| {
+            let text = if self.clipboard_numbers && self.html_renderer {
+                format!(
+                    "<span class=\"numthm-number\" data-clipboard-text=\"{}\">{text}</span>",
+                    text.replace('"', "&quot;")
+                )
+            } else {
+                text
+            };
+            // the margin tag is positioned by the `numthm-margin` CSS rule
+            if self.margin_tags && self.html_renderer {
+                format!("<span class=\"numthm-margin\">{text}</span>")
+            } else {
+                text
+            }
+        };
+        // starred environments have the default header
+        let templated = env.header_template.as_deref().and_then(|template| {
+            let n = n.as_deref()?;
+            let template = match template {
+                EQ_TAG if !self.html_renderer => EQ_TAG_PLAIN,
+                _ => template,
+            };
+            if title.is_some() && !template.contains("{title}") {
+                warn!("{num_name}: The title is dropped by header template `{template}'");
+            }
+            let num = clipboard(env.num(prefix, n));
+            header_from_template(
+                template,
+                &env.name,
+                &num,
+                title.as_deref().unwrap_or_default(),
+                &total,
+            )
+        });
+        let header = match (templated, title) {
+            (Some(header), _) => header,
+            (None, Some(title)) => {
+                format!(
+                    "{}{total_suffix} ({title}){punct}",
+                    clipboard(num_name.clone())
+                )
+            }
+            (None, None) => format!("{}{total_suffix}{punct}", clipboard(num_name.clone())),
+        };
+        let header = match line.map(str::trim) {
+            Some(line) if !line.is_empty() => format!("{header} {line}"),
+            _ => header,
+        };
+        let header = emphasize(&header, &emph, self.html_emphasis);
+        let header = match status {
+            Some(status) => format!(
+                "{header} <span class=\"numthm-status numthm-status-{status}\">{status}</span>"
+            ),
+            None => header,
+        };
+        let header = if self.emit_classes && self.html_renderer {
+            format!(
+                "<span class=\"numthm-env numthm-env-{}\">{header}</span>",
+                env.key
+            )
+        } else {
+            header
+        };
+        let num_name = n.is_some().then_some(num_name);
+        match label
+            .as_deref()
+            .map(|label| self.anchor_attribute.attrs(&anchor(label)))
+        {
+            // the anchor wraps the header so that the `:target` CSS rule applies to it
+            Some(attrs) if self.highlight_target => (
+                None,
+                format!("<a {attrs} class=\"{TARGET_CLASS}\">{header}</a>"),
+                num_name,
+            ),
+            Some(attrs) => (Some(format!("<a {attrs}></a>")), header, num_name),
+            None => (None, header, num_name),
+        }
+    }
+
+    /// Checks that labels defined in `s` start with the key of their environment (or an allowed alias)
+    /// followed by the separator, if the label prefix lint is enabled, and returns a warning for each offending label.
+    fn lint_label_prefixes(&self, s: &str, path: &Path) -> Vec<String> {
+        let lint = match &self.label_lint {
+            Some(lint) => lint,
+            None => return Vec::new(),
+        };
+
+        let mut warnings = Vec::new();
+        for env in &self.envs {
+            for (label, offset) in find_labels(&env.regex, s) {
+                let (line, _) = line_and_column(s, offset);
+                let location = format!("{}:{line}", path.display());
+                match label.split_once(lint.separator.as_str()) {
+                    Some((prefix, _)) => {
+                        let aliases = lint.aliases.get(&env.key);
+                        if prefix != env.key
+                            && !aliases.is_some_and(|a| a.iter().any(|a| a == prefix))
+                        {
+                            warnings.push(format!(
+                                "{location}: Label `{label}' of a `{}' environment has prefix `{prefix}'",
+                                env.key
+                            ));
+                        }
+                    }
+                    None if lint.strict => warnings.push(format!(
+                        "{location}: Label `{label}' of a `{}' environment has no prefix",
+                        env.key
+                    )),
+                    None => {}
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Finds all label definitions and references in `s`, which must be the source of chapter `path`
+    /// before any replacement, and appends them with their positions to `definitions` and `references`.
+    fn find_label_positions(
+        &self,
+        s: &str,
+        path: &Path,
+        definitions: &mut Vec<LabelPosition>,
+        references: &mut Vec<LabelPosition>,
+    ) {
+        let position = |label: String, key: &str, offset: usize| {
+            let (line, column) = line_and_column(s, offset);
+            LabelPosition {
+                label,
+                key: key.to_string(),
+                path: path.to_path_buf(),
+                offset,
+                line,
+                column,
+            }
+        };
+        let mut defs = Vec::new();
+        for env in &self.envs {
+            for (label, offset) in find_labels(&env.regex, s) {
+                defs.push(position(label, &env.key, offset));
+            }
+        }
+        defs.sort_by_key(|def| def.offset);
+        definitions.append(&mut defs);
+        for (labels, offset) in find_labels(&self.regexes.refs, s) {
+            for label in labels.split(',').flat_map(|label| label.split("--")) {
+                references.push(position(label.trim().to_string(), "", offset));
+            }
+        }
+    }
+
+    /// Same as `find_and_replace_all_refs`, ignoring unknown labels.
+    #[cfg(test)]
+    fn find_and_replace_refs(
+        &self,
+        s: &str,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+    ) -> String {
+        self.find_and_replace_all_refs(s, chap_path, refs, &mut Vec::new())
+    }
+
+    /// Returns the label referenced by `raw_label`, unescaped and normalized.
+    fn ref_label(&self, raw_label: &str) -> String {
+        normalize_label(
+            &unescape_label(raw_label, "Reference"),
+            &self.label_normalization,
+        )
+    }
+
+    /// Returns the environment of all labels of `labels` if they are the same numbered environment
+    /// without numbered name template, so that references to them can be written with its plural.
+    fn common_env(&self, labels: &[&str], refs: &HashMap<String, LabelInfo>) -> Option<&Env> {
+        let infos: Vec<&LabelInfo> = labels
+            .iter()
+            .map(|label| refs.get(&self.ref_label(label)))
+            .collect::<Option<_>>()?;
+        let key = &infos.first()?.key;
+        if infos
+            .iter()
+            .any(|info| &info.key != key || info.num.is_empty())
+        {
+            return None;
+        }
+        self.envs
+            .iter()
+            .find(|env| &env.key == key && env.template.is_none())
+    }
+
+    /// Returns the reference to the range of environments between labels `first` and `last`,
+    /// e.g. "Theorems 1.2.1–1.2.3" with links on both numbers, or `None` if they are different environments,
+    /// are located in different chapters, or are not numbered.
+    fn ref_range(
+        &self,
+        first: (&str, &LabelInfo),
+        last: (&str, &LabelInfo),
+        chap_path: &PathBuf,
+    ) -> Option<String> {
+        let ((first, a), (last, b)) = (first, last);
+        if a.key != b.key || a.path != b.path || a.num.is_empty() || b.num.is_empty() {
+            return None;
+        }
+        let env = self.envs.iter().find(|env| env.key == a.key)?;
+        if env.template.is_some() {
+            return None;
+        }
+        if a.excluded {
+            return Some(format!(
+                "{}{}{}–{}",
+                env.plural(),
+                self.name_separator,
+                a.num,
+                b.num
+            ));
+        }
+        let rel_path = compute_rel_path(chap_path, &a.path);
+        Some(format!(
+            "{}{}[{}]({rel_path}#{})–[{}]({rel_path}#{})",
+            env.plural(),
+            self.name_separator,
+            a.num,
+            anchor(first),
+            b.num,
+            anchor(last)
+        ))
+    }
+
+    /// Joins references to several labels, e.g. "Theorem 1, Lemma 2 and Lemma 3".
+    fn join_refs(&self, items: &[String]) -> String {
+        match items {
+            [] => String::new(),
+            [item] => item.clone(),
+            [init @ .., last] => {
+                let last_sep = if self.ref_oxford_comma && init.len() > 1 {
+                    format!(
+                        "{}{}",
+                        self.ref_join_separator.trim_end(),
+                        self.ref_join_last
+                    )
+                } else {
+                    self.ref_join_last.clone()
+                };
+                format!("{}{last_sep}{last}", init.join(&self.ref_join_separator))
+            }
+        }
+    }
+
+    /// Finds and replaces all patterns {{ref: label}} where label is an existing key in hashmap `refs`
+    /// with a link towards the relevant theorem, pushing unknown labels to `unknown`.
+    /// Patterns {{jsref: label}} are replaced with a link without text, to be filled in by a script
+    /// (or with the same link as {{ref: label}} if the book is not rendered to HTML).
+    fn find_and_replace_all_refs(
+        &self,
+        s: &str,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+        unknown: &mut Vec<String>,
+    ) -> String {
+        let re = &self.regexes.refs;
+        let any_ref_re = &self.regexes.any_ref;
+        // labels cited in citation mode, in order of first citation
+        let mut citations: Vec<String> = Vec::new();
+
+        // renders the reference to a single label, followed by `punct`, with the number only as text if `number_only`
+        let mut render = |raw_label: &str, reftype: &str, punct: &str, number_only: bool| {
+            let label = self.ref_label(raw_label);
+            if refs.contains_key(&label) {
+                let info = refs.get(&label).unwrap();
+                if reftype == "ref:" && self.citation_mode {
+                    let i = match citations.iter().position(|l| *l == label) {
+                        Some(i) => i + 1,
+                        None => {
+                            citations.push(label);
+                            citations.len()
+                        }
+                    };
+                    return format!("[\\[{i}\\]](#{CITATION_ANCHOR}{i}){punct}");
+                }
+                let text = match reftype {
+                    _ if number_only => info.num.clone(),
+                    "ref:" | "jsref:" => info.num_name.clone(),
+                    // fallback to the numbered name in case the environment is not numbered
+                    "sref:" if !info.num.is_empty() => info.num.clone(),
+                    "sref:" => info.num_name.clone(),
+                    "pref:" => format!("({})", info.num_name),
+                    "eqref:" if !info.num.is_empty() => format!("({})", info.num),
+                    "eqref:" => info.num_name.clone(),
+                    "tref:" => match &info.title {
+                        Some(t) => t.clone(),
+                        // fallback to the numbered name in case the label does not have an associated title
+                        None => info.num_name.clone(),
+                    },
+                    _ => {
+                        // this must be qtref if there is a match
+                        match &info.title {
+                            Some(t) => format!("{}, \"{t}\"", info.num_name),
+                            None => info.num_name.clone(),
+                        }
+                    }
+                };
+                // the template does not apply to the numbers of references written with a plural
+                let text = match &self.ref_template {
+                    Some(template) if !number_only => template.replace("{text}", &text),
+                    _ => text,
+                };
+                // environments of excluded chapters have no anchor
+                if info.excluded {
+                    return format!("{text}{punct}");
+                }
+                let path_to_ref = &info.path;
+                let rel_path = compute_rel_path(chap_path, path_to_ref);
+                let tooltip = if self.ref_location_tooltip {
+                    Some(location_tooltip(&info.section, &info.num_name))
+                } else {
+                    None
+                };
+                if reftype == "jsref:" && self.html_renderer {
+                    let title = tooltip.map_or(String::new(), |tooltip| {
+                        format!(" title=\"{}\"", tooltip.replace('"', "&quot;"))
+                    });
+                    return format!(
+                        "<a href=\"{rel_path}#{}\" class=\"numthm-jsref\" data-label=\"{}\"{title}></a>{punct}",
+                        anchor(&label),
+                        label.replace('"', "&quot;")
+                    );
+                }
+                let label = anchor(&label);
+                // the tooltip is the title of the link
+                let destination = match tooltip {
+                    Some(tooltip) => {
+                        format!("{rel_path}#{label} \"{}\"", tooltip.replace('"', "\\\""))
+                    }
+                    None => format!("{rel_path}#{label}"),
+                };
+                let (text, punct) = if self.absorb_trailing_punct {
+                    (format!("{text}{punct}"), "")
+                } else {
+                    (text, punct)
+                };
+                if self.print_pagerefs {
+                    format!(
+                        "[{text}]({destination}) (page {PAGEREF_OPEN}{label}{PAGEREF_CLOSE}){punct}"
+                    )
+                } else {
+                    format!("[{text}]({destination}){punct}")
+                }
+            } else {
+                warn!("Unknown reference: {}", label);
+                unknown.push(label.clone());
+                let missing = self.missing_ref_text.replace("{label}", &label);
+                if self.todo_unknown_refs {
+                    // "--" is not allowed in HTML comments
+                    let label = label.replace("--", "- -");
+                    format!("{missing}<!-- TODO: define label '{label}' -->{punct}")
+                } else {
+                    format!("{missing}{punct}")
+                }
+            }
+        };
+
+        let mut replacement = |caps: &regex::Captures| {
+            let reftype = caps.name("reftype").unwrap().as_str();
+            let punct = caps.name("punct").map_or("", |p| p.as_str());
+            // a reference to a range of labels `first--last` is rendered as "Theorems 1.2.1–1.2.3"
+            if let Some((first, last)) = caps["label"].split_once("--") {
+                let (first, last) = (first.trim(), last.trim());
+                let (first_label, last_label) = (self.ref_label(first), self.ref_label(last));
+                if reftype == "ref:" && !self.citation_mode {
+                    if let (Some(a), Some(b)) = (refs.get(&first_label), refs.get(&last_label)) {
+                        if let Some(range) =
+                            self.ref_range((&first_label, a), (&last_label, b), chap_path)
+                        {
+                            return format!("{range}{punct}");
+                        }
+                        warn!(
+                            "Reference range `{}' between different environments or chapters \
+                             (or with a numbered name template), referencing its endpoints separately",
+                            &caps["label"]
+                        );
+                    }
+                }
+                return format!(
+                    "{}–{}",
+                    render(first, reftype, "", false),
+                    render(last, reftype, punct, false)
+                );
+            }
+            // a reference may list several comma-separated labels, the punctuation goes after the last one
+            let labels: Vec<&str> = caps["label"].split(',').map(str::trim).collect();
+            let last = labels.len() - 1;
+            // references to several environments of the same kind read e.g. "Lemmas 2 and 3"
+            let env = match labels.len() {
+                1 => None,
+                _ if reftype != "ref:" || self.citation_mode => None,
+                _ => self.common_env(&labels, refs),
+            };
+            let items: Vec<String> = labels
+                .iter()
+                .enumerate()
+                .map(|(i, label)| {
+                    let punct = if i == last { punct } else { "" };
+                    render(label, reftype, punct, env.is_some())
+                })
+                .collect();
+            match env {
+                Some(env) => format!(
+                    "{}{}{}",
+                    env.plural(),
+                    self.name_separator,
+                    self.join_refs(&items)
+                ),
+                None => self.join_refs(&items),
+            }
+        };
+
+        let mut output = replace_outside_code_regions(s, |segment| {
+            for caps in any_ref_re.captures_iter(segment) {
+                let reftype = &caps["reftype"];
+                // `{{listof: ...}}` is replaced afterwards
+                if reftype != "listof" && !self.enabled_reftypes.iter().any(|r| r == reftype) {
+                    warn!(
+                        "Unknown or disabled reference type in `{}', leaving it as is",
+                        &caps[0]
+                    );
+                }
+            }
+            re.replace_all(segment, &mut replacement).to_string()
+        });
+        if !citations.is_empty() {
+            output.push_str("\n\n**References**\n");
+            for (i, label) in citations.iter().enumerate() {
+                let info = &refs[label];
+                let rel_path = compute_rel_path(chap_path, &info.path);
+                // environments of excluded chapters have no anchor
+                let target = if info.excluded {
+                    info.num_name.clone()
+                } else {
+                    format!("[{}]({rel_path}#{})", info.num_name, anchor(label))
+                };
+                output.push_str(&format!(
+                    "\n- <a {}></a>\\[{}\\] {target}",
+                    self.anchor_attribute
+                        .attrs(&format!("{CITATION_ANCHOR}{}", i + 1)),
+                    i + 1,
+                ));
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Replaces all proof blocks `{{proof}}{label}[title] ... {{proof-end}}` (or `... {{end:proof}}`) of `s`,
+    /// which may be nested, by a collapsible `<details>` element (or by a plain proof with an italicized lead-in
+    /// if `collapsible_proof` is not set or the renderer is not HTML); a title of the form "of label" links
+    /// the environment with this label. Markers in skip regions and code regions are ignored, but proof blocks
+    /// may contain code regions.
+    fn find_and_replace_block_envs(
+        &self,
+        s: &str,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+    ) -> String {
+        let blocks = Blocks {
+            re: &self.regexes.proof,
+            end_re: &self.regexes.proof_end,
+            untouched: untouched_ranges(s),
+        };
+        self.replace_blocks(s, 0, &blocks, chap_path, refs)
+    }
+
+    /// Replaces the proof blocks of `s`, which starts at offset `offset` of the text described by `blocks`.
+    fn replace_blocks(
+        &self,
+        s: &str,
+        offset: usize,
+        blocks: &Blocks,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+    ) -> String {
+        let collapsible = self.collapsible_proof && self.html_renderer;
+        let mut output = String::new();
+        let mut pos = 0;
+        while let Some(caps) = blocks.re.captures_at(s, pos) {
+            let directive = caps.get(0).unwrap();
+            if blocks.is_untouched(offset + directive.start()) {
+                output.push_str(&s[pos..directive.end()]);
+                pos = directive.end();
+                continue;
+            }
+            output.push_str(&s[pos..directive.start()]);
+            let (body, end) = blocks.body(&s[directive.end()..], offset + directive.end());
+            let body = self.replace_blocks(body, offset + directive.end(), blocks, chap_path, refs);
+            let lead = match caps.name("title").map(|t| unescape(t.as_str())) {
+                Some(title) => {
+                    let target = title
+                        .strip_prefix("of ")
+                        .map(|label| {
+                            normalize_label(
+                                &unescape_label(label.trim(), "Proof"),
+                                &self.label_normalization,
+                            )
+                        })
+                        .and_then(|label| Some((refs.get(&label)?, anchor(&label))));
+                    match target {
+                        Some((info, _)) if info.excluded => format!("Proof of {}", info.num_name),
+                        Some((info, label)) => {
+                            let rel_path = compute_rel_path(chap_path, &info.path);
+                            if collapsible {
+                                format!(
+                                    "Proof of <a href=\"{rel_path}#{label}\">{}</a>",
+                                    info.num_name
+                                )
+                            } else {
+                                format!("Proof of [{}]({rel_path}#{label})", info.num_name)
+                            }
+                        }
+                        None => match title.strip_prefix("of ") {
+                            // proofs linked to unlabeled environments are titled by their numbered name
+                            Some(num_name)
+                                if self.envs.iter().any(|env| {
+                                    num_name.starts_with(&format!(
+                                        "{}{}",
+                                        env.name, self.name_separator
+                                    ))
+                                }) =>
+                            {
+                                format!("Proof {title}")
+                            }
+                            _ => format!("Proof ({title})"),
+                        },
+                    }
+                }
+                None => "Proof".to_string(),
+            };
+            if let Some(label) = caps.name("label") {
+                let label = anchor(&normalize_label(
+                    &unescape_label(label.as_str(), "Proof"),
+                    &self.label_normalization,
+                ));
+                let attrs = self.anchor_attribute.attrs(&label);
+                output.push_str(&format!("<a {attrs}></a>\n"));
+            }
+            if collapsible {
+                output.push_str(&format!(
+                    "<details class=\"numthm-proof\">\n<summary>{lead}.</summary>\n\n{}\n\n</details>",
+                    body.trim()
+                ));
+            } else {
+                output.push_str(&format!("*{lead}.* {}", body.trim()));
+            }
+            pos = directive.end() + end;
+        }
+        output.push_str(&s[pos..]);
+        output
+    }
+
+    /// Formats all paragraphs starting with one of `proof_lead_words` followed by a lead-in ending with a period,
+    /// e.g. "Proof." or "Proof of Theorem 3.2.", outside code blocks and blockquotes: the lead-in is italicized,
+    /// linking the environment it names if it contains the numbered name or the title of a unique labeled environment,
+    /// and the QED marker is appended at the end of the paragraph unless the paragraph already contains one.
+    fn format_proofs(
+        &self,
+        s: &str,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+    ) -> String {
+        let re = match &self.regexes.proof_lead {
+            Some(re) => re,
+            None => return s.to_string(),
+        };
+
+        replace_outside_skip_regions(s, |segment| {
+            let mut output = String::new();
+            let mut fence: Option<&str> = None;
+            let mut paragraph_start = true;
+            let mut in_proof = false;
+            for line in segment.split_inclusive('\n') {
+                let (text, newline) = match line.strip_suffix('\n') {
+                    Some(text) => (text, "\n"),
+                    None => (line, ""),
+                };
+                let trimmed = text.trim_start();
+                if let Some(marker) = fence {
+                    if trimmed.starts_with(marker) {
+                        fence = None;
+                    }
+                    output.push_str(line);
+                    continue;
+                }
+                if trimmed.is_empty() {
+                    // the proof ends with the paragraph
+                    if in_proof {
+                        append_qed(&mut output);
+                        in_proof = false;
+                    }
+                    output.push_str(line);
+                    paragraph_start = true;
+                    continue;
+                }
+                let mut text = text.to_string();
+                if let Some(marker) = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m)) {
+                    fence = Some(marker);
+                } else if paragraph_start && !trimmed.starts_with('>') && text == trimmed {
+                    if let Some(caps) = re.captures(&text) {
+                        let lead = proof_target(&caps["lead"], &caps["rest"], chap_path, refs);
+                        text = format!("*{lead}*{}", &text[caps["lead"].len()..]);
+                        in_proof = true;
+                    }
+                }
+                if in_proof && text.contains(QED) {
+                    in_proof = false;
+                }
+                output.push_str(&text);
+                output.push_str(newline);
+                paragraph_start = false;
+            }
+            // the proof ends with the segment
+            if in_proof {
+                append_qed(&mut output);
+            }
+            output
+        })
+    }
+
+    /// Finds and replaces all patterns `{{open-problems}}` with a registry listing all labeled environments
+    /// with a status, grouped by status, with links to them.
+    fn find_and_replace_registry(
+        &self,
+        s: &str,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+    ) -> String {
+        let mut entries: Vec<(&String, &LabelInfo)> = refs.iter().collect();
+        entries.sort_by_key(|(_, info)| info.index);
+
+        let mut registry = String::new();
+        for (status, heading) in STATUSES {
+            let group: Vec<_> = entries
+                .iter()
+                .filter(|(_, info)| info.status.as_deref() == Some(status))
+                .collect();
+            registry.push_str(&format!("**{heading}** ({})\n\n", group.len()));
+            for (label, info) in group {
+                registry.push_str(&format!("- {}\n", list_entry(label, info, chap_path)));
+            }
+            if !registry.ends_with("\n\n") {
+                registry.push('\n');
+            }
+        }
+        let registry = registry.trim_end();
+
+        let directive = format!("{{{{{}{REGISTRY}}}}}", self.namespace());
+        replace_outside_code_regions(s, |segment| segment.replace(&directive, registry))
+    }
+
+    /// Finds and replaces all patterns `{{listof: key1 key2 ... tag=tag1 ...}}` with a list of links to all labeled
+    /// environments with one of the given keys (or to all labeled environments if no key is given)
+    /// and all the given tags, sorted according to `index_sort`.
+    fn find_and_replace_listof(
+        &self,
+        s: &str,
+        chap_path: &PathBuf,
+        refs: &HashMap<String, LabelInfo>,
+    ) -> String {
+        let mut entries: Vec<(&String, &LabelInfo)> = refs.iter().collect();
+        match self.index_sort {
+            IndexSort::Appearance => entries.sort_by_key(|(_, info)| info.index),
+            IndexSort::Title => entries.sort_by_cached_key(|(_, info)| {
+                let text = info.title.as_ref().unwrap_or(&info.num_name);
+                (text.to_lowercase(), info.index)
+            }),
+            IndexSort::Number => entries.sort_by_cached_key(|(_, info)| {
+                let numbers: Vec<u64> = NUMBER_RE
+                    .find_iter(&info.num_name)
+                    .filter_map(|n| n.as_str().parse().ok())
+                    .collect();
+                (numbers, info.index)
+            }),
+        }
+
+        let replacement = |caps: &regex::Captures| {
+            let (tags, keys): (Vec<&str>, Vec<&str>) = caps["keys"]
+                .split_whitespace()
+                .partition(|key| key.starts_with("tag="));
+            let tags: Vec<&str> = tags.iter().map(|tag| &tag["tag=".len()..]).collect();
+            entries
+                .iter()
+                .filter(|(_, info)| keys.is_empty() || keys.contains(&info.key.as_str()))
+                .filter(|(_, info)| tags.iter().all(|tag| info.tags.iter().any(|t| t == tag)))
+                .map(|(label, info)| format!("- {}", list_entry(label, info, chap_path)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        replace_outside_code_regions(s, |segment| {
+            self.regexes
+                .listof
+                .replace_all(segment, &replacement)
+                .to_string()
+        })
+    }
+}
+
+/// Appends the QED marker to `output`, before the final newline if any.
+fn append_qed(output: &mut String) {
+    let newline = output.ends_with('\n');
+    if newline {
+        output.pop();
+    }
+    output.push(' ');
+    output.push_str(QED);
+    if newline {
+        output.push('\n');
+    }
+}
+
+/// Returns lead-in `lead` of a proof where the numbered name or the title of the labeled environment
+/// named in `rest` (the part of the lead-in following the lead word), if unique, is replaced by a link to it.
+fn proof_target(
+    lead: &str,
+    rest: &str,
+    chap_path: &PathBuf,
+    refs: &HashMap<String, LabelInfo>,
+) -> String {
+    let rest_lowercase = rest.to_lowercase();
+    let offset = lead.len() - 1 - rest.len();
+    let mut matches = Vec::new();
+    for (label, info) in refs {
+        // the numbered name must not be followed by further digits, e.g. "Theorem 3.2" in "Theorem 3.21"
+        if let Some(start) = rest.find(&info.num_name) {
+            let end = start + info.num_name.len();
+            if !rest[end..].starts_with(|c: char| c.is_ascii_digit()) {
+                matches.push((label, info, start, end));
+                continue;
+            }
+        }
+        if let Some(title) = &info.title {
+            // the lowercase title is assumed to have the same length as the title
+            let title = title.to_lowercase();
+            if let Some(start) = rest_lowercase.find(&title) {
+                if rest_lowercase.len() == rest.len() {
+                    matches.push((label, info, start, start + title.len()));
+                }
+            }
+        }
+    }
+    match matches[..] {
+        [(label, info, start, end)] if !info.excluded => {
+            let rel_path = compute_rel_path(chap_path, &info.path);
+            let (start, end) = (offset + start, offset + end);
+            format!(
+                "{}[{}]({rel_path}#{}){}",
+                &lead[..start],
+                &lead[start..end],
+                anchor(label),
+                &lead[end..]
+            )
+        }
+        _ => lead.to_string(),
+    }
+}
+
+/// Returns the statement at the start of `s` (which directly follows the directive of an environment)
+/// together with the offset in `s` where the statement ends: the statement extends up to `end_marker`
+/// (which is dropped) if it occurs before the next directive matched by `re`, and up to the next blank line otherwise.
+fn quoted_statement<'a>(s: &'a str, re: &Regex, end_marker: &str) -> (&'a str, usize) {
+    let next_directive = re.find(s).map_or(s.len(), |m| m.start());
+    match s.find(end_marker) {
+        Some(i) if i < next_directive => (s[..i].trim_end(), i + end_marker.len()),
+        _ => {
+            let end = BLANK_LINE_RE.find(s).map_or(s.len(), |m| m.start());
+            (&s[..end], end)
+        }
+    }
+}
+
+/// The markers of proof blocks in some text, see `find_and_replace_block_envs`.
+struct Blocks<'a> {
+    /// The regex matching directives opening blocks.
+    re: &'a Regex,
+    /// The regex matching markers ending blocks.
+    end_re: &'a Regex,
+    /// The byte ranges of the untouched regions of the text, where markers are ignored.
+    untouched: Vec<std::ops::Range<usize>>,
+}
+
+impl Blocks<'_> {
+    /// Returns whether offset `pos` of the text lies in an untouched region.
+    fn is_untouched(&self, pos: usize) -> bool {
+        self.untouched.iter().any(|range| range.contains(&pos))
+    }
+
+    /// Returns the body of the block at the start of `s` (which directly follows the directive opening the block
+    /// and starts at offset `offset` of the text) together with the offset in `s` where the block ends:
+    /// the body extends up to the matching end marker (which is dropped), skipping nested blocks
+    /// and markers in untouched regions, or up to the end of `s`.
+    fn body<'s>(&self, s: &'s str, offset: usize) -> (&'s str, usize) {
+        let mut markers: Vec<(usize, usize, bool)> = self
+            .re
+            .find_iter(s)
+            .map(|m| (m.start(), m.end(), true))
+            .chain(
+                self.end_re
+                    .find_iter(s)
+                    .map(|m| (m.start(), m.end(), false)),
+            )
+            .filter(|(start, _, _)| !self.is_untouched(offset + start))
+            .collect();
+        markers.sort();
+        let mut depth = 0;
+        for (start, end, open) in markers {
+            match (open, depth) {
+                (true, _) => depth += 1,
+                (false, 0) => return (&s[..start], end),
+                (false, _) => depth -= 1,
+            }
+        }
+        warn!("Missing end marker of proof block");
+        (s, s.len())
+    }
+}
+
+/// The association of the proof blocks of a chapter with the environments they prove, see `link_proof_blocks`.
+#[derive(Default)]
+struct ProofLinks {
+    /// The numbered name and the label (if any) of the environment preceding the current position of the chapter,
+    /// which is `None` if this environment is unnumbered or already followed by a proof block,
+    /// or `None` if no environment precedes.
+    preceding: Option<Option<(String, Option<String>)>>,
+    /// The anchors of the deferred proof blocks of the environments of the chapter, indexed by label.
+    deferred: HashMap<String, String>,
+}
+
+/// Returns `s` as a blockquote, i.e., with each line prefixed by "> " (or by ">" for blank lines).
+fn quote(s: &str) -> String {
+    s.lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                ">".to_string()
+            } else {
+                format!("> {line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Applies `func` to all items of `items` and their sub-items, in the order of the summary,
+/// i.e., each chapter before its sub-chapters (unlike `Book::for_each_mut`).
+fn for_each_mut_in_order(items: &mut [BookItem], func: &mut impl FnMut(&mut BookItem)) {
+    for item in items {
+        func(item);
+        if let BookItem::Chapter(chapter) = item {
+            for_each_mut_in_order(&mut chapter.sub_items, func);
+        }
+    }
+}
+
+/// Returns the letter numbering the appendix at position `i` (starting at 0): "A", ..., "Z", "AA", "AB", etc.
+fn appendix_letter(i: usize) -> String {
+    let letter = char::from(b'A' + (i % 26) as u8);
+    match i / 26 {
+        0 => letter.to_string(),
+        q => format!("{}{letter}", appendix_letter(q - 1)),
+    }
+}
+
+/// Replaces the total placeholders of `s` with the totals of their counters given by `totals`.
+fn fill_totals(s: &str, totals: &HashMap<String, usize>) -> String {
+    TOTAL_RE
+        .replace_all(s, |caps: &regex::Captures| {
+            totals
+                .get(&caps["counter"])
+                .map_or("?".to_string(), |total| total.to_string())
+        })
+        .to_string()
+}
+
+/// Returns the header obtained by replacing placeholders `{name}`, `{num}`, `{title}`, and `{total}` of `template`,
+/// or `None` (with a warning) if the template contains an unterminated placeholder.
+fn header_from_template(
+    template: &str,
+    name: &str,
+    num: &str,
+    title: &str,
+    total: &str,
+) -> Option<String> {
+    let mut header = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        header.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                warn!("Unterminated placeholder in header template `{template}', using the default header");
+                return None;
+            }
+        };
+        match &rest[start + 1..end] {
+            "name" => header.push_str(name),
+            "num" => header.push_str(num),
+            "title" => header.push_str(title),
+            "total" => header.push_str(total),
+            // unknown placeholders are kept as is
+            _ => header.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    header.push_str(rest);
+    Some(header)
+}
+
+/// Returns counter value `n` written according to `style`, e.g. "iv" for 4 with lowercase Roman numerals;
+/// 0 (which has no Roman or alphabetic representation) is always written "0".
+fn format_counter(n: usize, style: NumberingStyle) -> String {
+    const ROMAN: [(usize, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    if n == 0 {
+        return n.to_string();
+    }
+    match style {
+        NumberingStyle::Arabic => n.to_string(),
+        NumberingStyle::RomanLower => format_counter(n, NumberingStyle::RomanUpper).to_lowercase(),
+        NumberingStyle::RomanUpper => {
+            let mut n = n;
+            let mut roman = String::new();
+            for (value, numeral) in ROMAN {
+                while n >= value {
+                    roman.push_str(numeral);
+                    n -= value;
+                }
+            }
+            roman
+        }
+        NumberingStyle::AlphaLower => appendix_letter(n - 1).to_lowercase(),
+        NumberingStyle::AlphaUpper => appendix_letter(n - 1),
+    }
+}
+
+/// Returns the regex matching the paths matched by glob pattern `pattern`, where `*` matches any sequence
+/// of characters other than '/', `**` matches any sequence of characters, and `?` matches any character other than '/'.
+fn glob_regex(pattern: &str) -> Regex {
+    let pattern = regex::escape(pattern)
+        .replace(r"\*\*", ".*")
+        .replace(r"\*", "[^/]*")
+        .replace(r"\?", "[^/]");
+    Regex::new(&format!("^{pattern}$")).unwrap()
+}
+
+/// Returns the offsets and levels of the ATX headings of levels 2 to `depth` of `s`, e.g. `## Subgroups`,
+/// outside skip regions and code regions.
+fn find_headings(s: &str, depth: usize) -> Vec<(usize, usize)> {
+    let mut headings = Vec::new();
+    let mut offset = 0;
+    for (skipped, segment) in split_skip_regions(s) {
+        if !skipped {
+            let mut code_offset = offset;
+            for (code, code_segment) in split_code_regions(segment) {
+                if !code {
+                    for caps in HEADING_RE.captures_iter(code_segment) {
+                        let start = code_offset + caps.get(0).unwrap().start();
+                        let level = caps[1].len();
+                        // the segment may start in the middle of a line, after a code span
+                        let line_start = start == 0 || s.as_bytes()[start - 1] == b'\n';
+                        if line_start && level <= depth {
+                            headings.push((start, level));
+                        }
+                    }
+                }
+                code_offset += code_segment.len();
+            }
+        }
+        offset += segment.len();
+    }
+    headings
+}
+
+/// Returns the prefix made of the first `depth` levels of section number `sn`, each followed by `separator`,
+/// e.g. "1." for "1.2.3." with depth 1 and separator ".".
+fn truncate_section_number(sn: &SectionNumber, depth: usize, separator: &str) -> String {
+    sn.iter()
+        .take(depth)
+        .map(|n| format!("{n}{separator}"))
+        .collect()
+}
+
+/// Returns the prefix derived from the file stem of chapter `path` followed by `separator`, e.g. "groups." for
+/// "algebra/groups.md", where characters other than alphanumeric characters, '-', and '_' are replaced by '-'.
+fn filename_prefix(path: &Path, separator: &str) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let stem: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{stem}{separator}")
+}
+
+/// Applies emphasis `emph` (a markdown delimiter such as "**") to `text`,
+/// using the equivalent HTML tags if `html` is true and the delimiter is a standard one.
+fn emphasize(text: &str, emph: &str, html: bool) -> String {
+    match emph {
+        "**" | "__" if html => format!("<strong>{text}</strong>"),
+        "*" | "_" if html => format!("<em>{text}</em>"),
+        "***" | "___" if html => format!("<strong><em>{text}</em></strong>"),
+        _ => format!("{emph}{text}{emph}"),
+    }
+}
+
+/// Formats a link to the environment labeled `label` for a list of environments.
+fn list_entry(label: &str, info: &LabelInfo, chap_path: &PathBuf) -> String {
+    // environments of excluded chapters have no anchor
+    if info.excluded {
+        return match &info.title {
+            Some(title) => format!("{} ({title})", info.num_name),
+            None => info.num_name.clone(),
+        };
+    }
+    let rel_path = compute_rel_path(chap_path, &info.path);
+    let label = anchor(label);
+    match &info.title {
+        Some(title) => format!("[{} ({title})]({rel_path}#{label})", info.num_name),
+        None => format!("[{}]({rel_path}#{label})", info.num_name),
+    }
+}
+
+/// Parses a whitespace-separated list of attributes `key=value` or `key="value"`.
+fn parse_attributes(s: &str) -> Vec<(String, String)> {
+    ATTRIBUTE_RE
+        .captures_iter(s)
+        .map(|caps| {
+            let value = caps.name("quoted").or(caps.name("value")).unwrap();
+            (caps["key"].to_string(), value.as_str().to_string())
+        })
+        .collect()
+}
+
+/// Builds the environment defined by table `table`, resolving inheritance: an environment specifying
+/// `inherits = "key"` inherits all unspecified fields from the environment with key `key`, which is looked up
+/// in `tables` (environments defined by tables) and then in `builtins`;
+/// `chain` is the list of keys of the environments inheriting from `table`, used to detect cycles.
+fn resolve_env(
+    table: &toml::value::Table,
+    tables: &HashMap<&str, &toml::value::Table>,
+    builtins: &[Env],
+    chain: &mut Vec<String>,
+) -> Option<Env> {
+    let key = table.get("key")?.as_str()?;
+    let parent = match table.get("inherits") {
+        Some(toml::Value::String(parent_key)) => {
+            chain.push(key.to_string());
+            if chain.contains(parent_key) {
+                warn!("Inheritance cycle: {} -> {parent_key}", chain.join(" -> "));
+                return None;
+            }
+            let parent = match tables.get(parent_key.as_str()) {
+                Some(parent_table) => resolve_env(parent_table, tables, builtins, chain)?,
+                None => {
+                    match builtins.iter().find(|env| env.key == *parent_key) {
+                        Some(env) => env.clone(),
+                        None => {
+                            warn!("Environment `{key}' inherits from unknown environment `{parent_key}'");
+                            return None;
+                        }
+                    }
+                }
+            };
+            Some(parent)
+        }
+        _ => None,
+    };
+    Env::from_table(table, parent.as_ref())
+}
+
+/// Returns the regex matching `{{ref: label}}` and other reference forms, the reference type being preceded by
+/// `namespace` (see `NumThmPreprocessor::namespace`).
+fn ref_regex(namespace: &str, reftypes: &[String]) -> Regex {
+    // see https://regex101.com/ for an explanation of the regex
+    // the label may contain escaped braces `\{` and `\}`, as well as unescaped ones (which are warned about)
+    // as long as they are not followed by another closing brace
+    // the punctuation immediately following the reference is captured for `absorb_trailing_punct`
+    let mut pattern = r"\{\{".to_string();
+    pattern.push_str(&regex::escape(namespace));
+    pattern.push_str("(?P<reftype>");
+    pattern.push_str(
+        &reftypes
+            .iter()
+            .map(|r| format!("{r}:"))
+            .collect::<Vec<_>>()
+            .join("|"),
+    );
+    pattern.push_str(r")\s*(?P<label>(?:\\.|[^\\}\n]|\}[^}\n])*?)\}\}(?P<punct>[.,])?");
+    Regex::new(&pattern).unwrap()
+}
+
+/// Returns the regex matching directives which look like references of any type, e.g. `{{xref: label}}`.
+/// Returns the regex matching references such as `{{ref: label}}` (with any reference type), also in the
+/// forms with spaces before the reference type or before the colon, such as `{{ ref :label}}`, which are
+/// not recognized as references but reported by the `check` subcommand.
+fn loose_ref_regex(namespace: &str) -> Regex {
+    let mut pattern = r"\{\{(?P<lead>\s*)".to_string();
+    pattern.push_str(&regex::escape(namespace));
+    pattern.push_str(
+        r"(?P<reftype>[[:alpha:]]+)(?P<colon>\s*):\s*(?P<label>(?:\\.|[^\\}\n]|\}[^}\n])*?)\}\}",
+    );
+    Regex::new(&pattern).unwrap()
+}
+
+fn any_ref_regex(namespace: &str) -> Regex {
+    let mut pattern = r"\{\{".to_string();
+    pattern.push_str(&regex::escape(namespace));
+    pattern.push_str(r"(?P<reftype>[[:alpha:]]+):[^}\n]*\}\}");
+    Regex::new(&pattern).unwrap()
+}
+
+/// Removes the backslashes escaping braces in `s`.
+fn unescape(s: &str) -> String {
+    s.replace(r"\{", "{").replace(r"\}", "}")
+}
+
+/// Escapes the braces in `s`, so that it can be used as a label in a directive.
+fn escape(s: &str) -> String {
+    s.replace('{', r"\{").replace('}', r"\}")
+}
+
+/// Unescapes label `raw` as captured in a directive, warning about unescaped braces;
+/// `context` identifies the directive in the warning.
+fn unescape_label(raw: &str, context: &str) -> String {
+    if raw
+        .replace(r"\{", "")
+        .replace(r"\}", "")
+        .contains(['{', '}'])
+    {
+        warn!(
+            "{context}: Unescaped brace in label `{raw}', use `\\{{' or `\\}}' for literal braces"
+        );
+    }
+    unescape(raw)
+}
+
+/// Returns label `label` normalized by applying operations `ops` in order, e.g. "my-theorem" for " My Theorem "
+/// with operations `["trim", "lowercase", { replace = " ", with = "-" }]`.
+fn normalize_label(label: &str, ops: &[LabelOp]) -> String {
+    ops.iter()
+        .fold(label.to_string(), |label, op| op.apply(&label))
+}
+
+/// Returns the location of an environment with numbered name `num_name` in a chapter with section number `section`,
+/// e.g. "Chapter 3 > Section 3.2 > Theorem 3.2.1" for section number `[3, 2]`.
+fn location_tooltip(section: &[u32], num_name: &str) -> String {
+    let mut location = Vec::new();
+    for depth in 1..=section.len() {
+        let number: Vec<String> = section[..depth].iter().map(|n| n.to_string()).collect();
+        let kind = if depth == 1 { "Chapter" } else { "Section" };
+        location.push(format!("{kind} {}", number.join(".")));
+    }
+    location.push(num_name.to_string());
+    location.join(" > ")
+}
+
+/// Returns the anchor name for label `label`, in which characters that are not allowed
+/// (or troublesome) in URL fragments are percent-encoded.
+fn anchor(label: &str) -> String {
+    let mut anchor = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            ' ' | '"' | '%' | '<' | '>' | '`' | '{' | '}' => {
+                anchor.push_str(&format!("%{:02X}", c as u32))
+            }
+            _ => anchor.push(c),
+        }
+    }
+    anchor
+}
+
+/// Replaces label `old` by label `new` in all matches of `re` (which must have a `label` group) in `s`,
+/// outside skip regions and code regions, and returns the result together with the number of replacements;
+/// if `list` is true, the captured label is a list of labels as in references (see `rename_in_label_list`).
+fn rename_in_matches(re: &Regex, s: &str, old: &str, new: &str, list: bool) -> (String, usize) {
+    let mut count = 0;
+    let mut replacement = |caps: &regex::Captures| {
+        let whole = caps.get(0).unwrap();
+        match caps.name("label") {
+            Some(label) => {
+                let renamed = if list {
+                    rename_in_label_list(label.as_str(), old, new, &mut count)
+                } else if unescape(label.as_str()) == old {
+                    count += 1;
+                    escape(new)
+                } else {
+                    return whole.as_str().to_string();
+                };
+                let before = &whole.as_str()[..label.start() - whole.start()];
+                let after = &whole.as_str()[label.end() - whole.start()..];
+                format!("{before}{renamed}{after}")
+            }
+            None => whole.as_str().to_string(),
+        }
+    };
+    let output = replace_outside_code_regions(s, |segment| {
+        re.replace_all(segment, &mut replacement).to_string()
+    });
+    (output, count)
+}
+
+/// Replaces label `old` by label `new` in `labels`, which is a comma-separated list of labels or a range
+/// `first--last` as in references, keeping the separators and the whitespace around labels,
+/// and increments `count` for each replacement.
+fn rename_in_label_list(labels: &str, old: &str, new: &str, count: &mut usize) -> String {
+    replace_in_label_list(labels, |label| {
+        if unescape(label) == old {
+            *count += 1;
+            Some(escape(new))
+        } else {
+            None
+        }
+    })
+}
+
+/// Replaces each (trimmed and still escaped) label of `labels`, which is a comma-separated list of labels
+/// or a range `first--last` as in references, by the result of `replace` if any, keeping the separators
+/// and the whitespace around labels.
+fn replace_in_label_list(labels: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::new();
+    let mut rest = labels;
+    loop {
+        let end = [rest.find(','), rest.find("--")]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(rest.len());
+        let item = &rest[..end];
+        let label = item.trim();
+        if let Some(replacement) = replace(label) {
+            let start = item.len() - item.trim_start().len();
+            output.push_str(&item[..start]);
+            output.push_str(&replacement);
+            output.push_str(&item[start + label.len()..]);
+        } else {
+            output.push_str(item);
+        }
+        if end == rest.len() {
+            return output;
+        }
+        let separator = if rest[end..].starts_with("--") { 2 } else { 1 };
+        output.push_str(&rest[end..end + separator]);
+        rest = &rest[end + separator..];
+    }
+}
+
+/// Applies `edits` (byte ranges of `s` with their replacements, which must not overlap) to `s` and returns the
+/// result (`None` if there are no edits) together with the changed lines, each group of lines being preceded
+/// by the number of its first line, e.g. "@@ line 3 @@\n-old line\n+new line\n".
+fn apply_edits(
+    s: &str,
+    mut edits: Vec<(std::ops::Range<usize>, String)>,
+) -> (Option<String>, String) {
+    if edits.is_empty() {
+        return (None, String::new());
+    }
+    edits.sort_by_key(|(range, _)| range.start);
+    let line_start = |pos: usize| s[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = |pos: usize| s[pos..].find('\n').map_or(s.len(), |i| pos + i);
+    let mut output = String::new();
+    let mut diff = String::new();
+    // the end of the lines already copied to `output`
+    let mut pos = 0;
+    let mut edits = edits.into_iter().peekable();
+    while let Some((range, replacement)) = edits.next() {
+        // the group of edits of the lines of this edit, extended by the edits of the same lines
+        let start = line_start(range.start);
+        let mut end = line_end(range.end);
+        let mut old = range.end;
+        let mut new = format!("{}{replacement}", &s[start..range.start]);
+        while let Some((range, replacement)) = edits.next_if(|(range, _)| range.start <= end) {
+            new.push_str(&s[old..range.start]);
+            new.push_str(&replacement);
+            old = range.end;
+            end = line_end(range.end);
+        }
+        new.push_str(&s[old..end]);
+        output.push_str(&s[pos..start]);
+        output.push_str(&new);
+        pos = end;
+        diff.push_str(&format!("@@ line {} @@\n", line_and_column(s, start).0));
+        for line in s[start..end].split('\n') {
+            diff.push_str(&format!("-{line}\n"));
+        }
+        for line in new.split('\n') {
+            diff.push_str(&format!("+{line}\n"));
+        }
+    }
+    output.push_str(&s[pos..]);
+    (Some(output), diff)
+}
+
+/// Returns all labels captured by regex `re` (which must have a `label` group) in `s`, outside skip regions
+/// and code regions, together with the byte offset in `s` of the corresponding match.
+fn find_labels(re: &Regex, s: &str) -> Vec<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut offset = 0;
+    for (untouched, segment) in split_untouched_regions(s) {
+        if !untouched {
+            for caps in re.captures_iter(segment) {
+                if let Some(label) = caps.name("label") {
+                    let start = offset + caps.get(0).unwrap().start();
+                    labels.push((unescape(label.as_str()), start));
+                }
+            }
+        }
+        offset += segment.len();
+    }
+    labels
+}
+
+/// Returns the line and column (both starting at 1, the column being counted in characters)
+/// corresponding to byte offset `offset` in `s`.
+fn line_and_column(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Returns the JSON export of label definitions and references, completed with the information from `refs`.
+fn export_labels(
+    definitions: &[LabelPosition],
+    references: &[LabelPosition],
+    refs: &HashMap<String, LabelInfo>,
+) -> serde_json::Value {
+    let definitions: Vec<_> = definitions
+        .iter()
+        .map(|def| {
+            // a duplicate definition is not registered in `refs`
+            let info = refs
+                .get(&def.label)
+                .filter(|info| info.path == def.path && info.key == def.key);
+            serde_json::json!({
+                "label": def.label,
+                "path": def.path,
+                "offset": def.offset,
+                "line": def.line,
+                "column": def.column,
+                "key": def.key,
+                "num_name": info.map(|info| &info.num_name),
+                "title": info.and_then(|info| info.title.as_ref()),
+            })
+        })
+        .collect();
+    let references: Vec<_> = references
+        .iter()
+        .map(|r| {
+            serde_json::json!({
+                "label": r.label,
+                "path": r.path,
+                "offset": r.offset,
+                "line": r.line,
+                "column": r.column,
+                "resolved": refs.contains_key(&r.label),
+            })
+        })
+        .collect();
+    serde_json::json!({ "definitions": definitions, "references": references })
+}
+
+/// Splits `s` into segments tagged with a boolean indicating whether the segment
+/// is a skip region, i.e. lies between `<!-- numthm:skip-start -->` and `<!-- numthm:skip-end -->`
+/// or between `<!-- numthm:processed-start -->` and `<!-- numthm:processed-end -->` (markers included);
+/// an unterminated skip region extends to the end of `s`.
+fn split_skip_regions(s: &str) -> Vec<(bool, &str)> {
+    let mut segments = Vec::new();
+    let mut rest = s;
+    loop {
+        // the first region starting in `rest`, if any
+        let region = [(SKIP_START, SKIP_END), (PROCESSED_START, PROCESSED_END)]
+            .into_iter()
+            .filter_map(|(start_marker, end_marker)| {
+                Some((rest.find(start_marker)?, start_marker, end_marker))
+            })
+            .min_by_key(|(start, _, _)| *start);
+        let (start, start_marker, end_marker) = match region {
+            Some(region) => region,
+            None => break,
+        };
+        segments.push((false, &rest[..start]));
+        let end = match rest[start..].find(end_marker) {
+            Some(i) => start + i + end_marker.len(),
+            None => {
+                warn!("Unterminated `{start_marker}' region");
+                rest.len()
+            }
+        };
+        segments.push((true, &rest[start..end]));
+        rest = &rest[end..];
+    }
+    segments.push((false, rest));
+    segments
+}
+
+/// Applies `replace` to all segments of `s` outside skip regions and leaves skip regions untouched.
+fn replace_outside_skip_regions(s: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    split_skip_regions(s)
+        .into_iter()
+        .map(|(skipped, segment)| {
+            if skipped {
+                segment.to_string()
+            } else {
+                replace(segment)
+            }
+        })
+        .collect()
+}
+
+/// Splits `s` into segments tagged with a boolean indicating whether the segment is a code region,
+/// i.e. a fenced code block (delimited by lines starting with at least three backticks or tildes)
+/// or an inline code span (delimited by backtick strings of the same length), or an HTML comment;
+/// an unterminated code block or comment extends to the end of `s`.
+fn split_code_regions(s: &str) -> Vec<(bool, &str)> {
+    let mut segments = Vec::new();
+    // the start of the current segment
+    let mut start = 0;
+    let mut offset = 0;
+    // the fence character and length of the current code block, if any
+    let mut fence: Option<(char, usize)> = None;
+    // whether an HTML comment is open at the start of the current line
+    let mut in_comment = false;
+    for line in s.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let indented = line.len() - trimmed.len() >= 4;
+        let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let marker_len = marker.map_or(0, |c| trimmed.len() - trimmed.trim_start_matches(c).len());
+        match fence {
+            Some((c, len)) => {
+                if !indented
+                    && marker == Some(c)
+                    && marker_len >= len
+                    && trimmed[marker_len..].trim().is_empty()
+                {
+                    segments.push((true, &s[start..offset + line.len()]));
+                    start = offset + line.len();
+                    fence = None;
+                }
+            }
+            None if in_comment => in_comment = open_comment(line, true),
+            None => {
+                if !indented && marker_len >= 3 {
+                    split_comments(&s[start..offset], &mut segments);
+                    start = offset;
+                    fence = Some((marker.unwrap(), marker_len));
+                } else {
+                    in_comment = open_comment(line, false);
+                }
+            }
+        }
+        offset += line.len();
+    }
+    match fence {
+        Some(_) => segments.push((true, &s[start..])),
+        None => split_comments(&s[start..], &mut segments),
+    }
+    segments
+}
+
+/// Returns whether an HTML comment is open at the end of `line`, given whether one is open at its start.
+fn open_comment(line: &str, mut open: bool) -> bool {
+    let mut rest = line;
+    loop {
+        let (marker, next) = if open { ("-->", false) } else { ("<!--", true) };
+        match rest.find(marker) {
+            Some(i) => {
+                rest = &rest[i + marker.len()..];
+                open = next;
+            }
+            None => return open,
+        }
+    }
+}
+
+/// Splits `s` (which contains no code block) into segments tagged with a boolean indicating whether the segment
+/// is an HTML comment or an inline code span, and appends them to `segments`.
+fn split_comments<'a>(s: &'a str, segments: &mut Vec<(bool, &'a str)>) {
+    let mut rest = s;
+    while let Some(start) = rest.find("<!--") {
+        split_code_spans(&rest[..start], segments);
+        let end = rest[start..]
+            .find("-->")
+            .map_or(rest.len(), |i| start + i + "-->".len());
+        segments.push((true, &rest[start..end]));
+        rest = &rest[end..];
+    }
+    split_code_spans(rest, segments);
+}
+
+/// Splits `s` (which contains no code block nor HTML comment) into segments tagged with a boolean
+/// indicating whether the segment is an inline code span, and appends them to `segments`; backtick strings without a closing string of the same
+/// length are not code span delimiters.
+fn split_code_spans<'a>(s: &'a str, segments: &mut Vec<(bool, &'a str)>) {
+    // the lengths and offsets of all backtick strings of `s`
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, c) in s.char_indices() {
+        if c == '`' {
+            match runs.last_mut() {
+                Some((len, offset)) if *offset + *len == i => *len += 1,
+                _ => runs.push((1, i)),
+            }
+        }
+    }
+    let mut start = 0;
+    let mut i = 0;
+    while i < runs.len() {
+        let (len, offset) = runs[i];
+        match runs[i + 1..].iter().position(|(l, _)| *l == len) {
+            Some(j) => {
+                let end = runs[i + 1 + j].1 + len;
+                segments.push((false, &s[start..offset]));
+                segments.push((true, &s[offset..end]));
+                start = end;
+                i += j + 2;
+            }
+            None => i += 1,
+        }
+    }
+    segments.push((false, &s[start..]));
+}
+
+/// Splits `s` (which contains no code region) into segments tagged with a boolean indicating whether the segment
+/// is an escaped directive opening `\{{`, i.e. preceded by an odd number of backslashes.
+fn split_escapes(s: &str) -> Vec<(bool, &str)> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for (i, _) in s.match_indices("{{") {
+        let backslashes = s[..i].len() - s[..i].trim_end_matches('\\').len();
+        if i >= start && backslashes % 2 == 1 {
+            segments.push((false, &s[start..i - 1]));
+            segments.push((true, &s[i - 1..i + 2]));
+            start = i + 2;
+        }
+    }
+    segments.push((false, &s[start..]));
+    segments
+}
+
+/// Splits `s` into segments tagged with a boolean indicating whether the segment is untouched by directives,
+/// i.e. is a skip region, a code region, or an escaped directive opening.
+fn split_untouched_regions(s: &str) -> Vec<(bool, &str)> {
+    split_skip_regions(s)
+        .into_iter()
+        .flat_map(|(skipped, segment)| {
+            if skipped {
+                return vec![(true, segment)];
+            }
+            split_code_regions(segment)
+                .into_iter()
+                .flat_map(|(code, segment)| {
+                    if code {
+                        vec![(true, segment)]
+                    } else {
+                        split_escapes(segment)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the byte ranges of the untouched regions of `s` (see `split_untouched_regions`).
+fn untouched_ranges(s: &str) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    for (untouched, segment) in split_untouched_regions(s) {
+        if untouched {
+            ranges.push(offset..offset + segment.len());
+        }
+        offset += segment.len();
+    }
+    ranges
+}
+
+/// Applies `replace` to all segments of `s` outside skip regions, code regions, and escaped directive openings,
+/// and leaves them untouched.
+fn replace_outside_code_regions(s: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    split_untouched_regions(s)
+        .into_iter()
+        .map(|(untouched, segment)| {
+            if untouched {
+                segment.to_string()
+            } else {
+                replace(segment)
+            }
+        })
+        .collect()
+}
+
+/// Removes the backslash of escaped directive openings `\{{` of `s` outside skip regions and code regions.
+fn strip_escapes(s: &str) -> String {
+    replace_outside_skip_regions(s, |segment| {
+        split_code_regions(segment)
+            .into_iter()
+            .flat_map(|(code, segment)| {
+                if code {
+                    vec![(false, segment)]
+                } else {
+                    split_escapes(segment)
+                }
+            })
+            .map(|(escaped, segment)| if escaped { &segment[1..] } else { segment })
+            .collect()
+    })
+}
+
+/// Computes the relative path from the folder containing `chap_path` to the file `path_to_ref`.
+fn compute_rel_path(chap_path: &PathBuf, path_to_ref: &PathBuf) -> String {
+    if chap_path == path_to_ref {
+        return "".to_string();
+    }
+    let mut local_chap_path = chap_path.clone();
+    local_chap_path.pop();
+    format!(
+        "{}",
+        diff_paths(path_to_ref, &local_chap_path).unwrap().display()
+    )
+}
+
+/// Writes the stylesheet and script highlighting targeted environments into directory `dir`.
+///
+/// Files which already exist with the right content are left untouched,
+/// so that installing several times is harmless.
+/// Returns the list of files which were actually written.
+pub fn install_assets(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for (file_name, content) in [("numthm.css", CSS), ("numthm.js", JS)] {
+        let file_path = dir.join(file_name);
+        if std::fs::read_to_string(&file_path).ok().as_deref() != Some(content) {
+            std::fs::write(&file_path, content)?;
+            written.push(file_path);
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lazy_static::lazy_static;
+    use mdbook::book::Chapter;
+    use mdbook::Config;
+    use std::str::FromStr;
+
+    const SECNUM: &str = "1.2.";
+
+    lazy_static! {
+        static ref THM: Env = Env::new("thm", "Theorem", "**");
+        static ref PROP: Env = Env::new("prop", "Proposition", "**");
+        static ref PATH: PathBuf = "crypto/groups.md".into();
+    }
+
+    /// Builds a preprocessor context from the content of a `book.toml` file.
+    fn test_ctx(book_toml: &str) -> PreprocessorContext {
+        let config = Config::from_str(book_toml).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "root": "",
+            "config": config,
+            "renderer": "html",
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        }))
+        .unwrap()
+    }
+
+    /// Builds a book with one numbered chapter per `(path, content)` pair.
+    fn test_book(chapters: &[(&str, &str)]) -> Book {
+        let mut book = Book::new();
+        for (i, (path, content)) in chapters.iter().enumerate() {
+            let mut chapter = Chapter::new(path, content.to_string(), path, Vec::new());
+            chapter.number = Some(SectionNumber(vec![i as u32 + 1]));
+            book.push_item(chapter);
+        }
+        book
+    }
+
+    /// Returns the content of the chapters of `book`.
+    fn contents(book: &Book) -> Vec<String> {
+        book.iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wo_label_wo_title() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from("**Proposition 1.2.1.**");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn wo_label_wo_title_example_cor_exer() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{example}} {{cor}} {{exer}} {{example}}");
+        let output = pre.find_and_replace_all_envs(
+            &input,
+            SECNUM,
+            &PATH,
+            &pre.envs,
+            &mut HashMap::new(),
+            &mut refs,
+            &mut ProofLinks::default(),
+        );
+        let expected = String::from(
+            "*Example 1.2.1.* **Corollary 1.2.1.** *Exercise 1.2.1.* *Example 1.2.2.*",
+        );
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn with_label_wo_title() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(
+            *refs.get("prop:lagrange").unwrap(),
+            LabelInfo {
+                key: "prop".to_string(),
+                num_name: "Proposition 1.2.1".to_string(),
+                num: "1.2.1".to_string(),
+                path: "crypto/groups.md".into(),
+                title: None,
+                status: None,
+                tags: Vec::new(),
+                index: 0,
+                redefinitions: Vec::new(),
+                source: "prop:lagrange".to_string(),
+                collisions: Vec::new(),
+                section: Vec::new(),
+                excluded: false,
+            }
+        )
+    }
+
+    #[test]
+    fn wo_label_with_title() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}[Lagrange Theorem]");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from("**Proposition 1.2.1 (Lagrange Theorem).**");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn with_label_with_title() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn double_label() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(
+            r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{thm}}{prop:lagrange}[Another Lagrange Theorem]",
+        );
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let output =
+            pre.find_and_replace_envs(&output, SECNUM, &PATH, &THM, &mut HashMap::new(), &mut refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).** \
+            <a id=\"prop:lagrange\"></a>\n\
+            **Theorem 1.2.1 (Another Lagrange Theorem).**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 1);
+    }
+
+    #[test]
+    fn label_and_ref_in_same_file() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input =
+            String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{ref: prop:lagrange}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let output = pre.find_and_replace_refs(&output, &PATH, &refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1 (Lagrange Theorem).** \
+            [Proposition 1.2.1](#prop:lagrange)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn label_and_ref_in_different_files() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/groups.md".into();
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{ref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from("[Proposition 1.2.1](../math/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn label_and_ref_in_different_files_2() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{ref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn title_ref() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{tref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from("[Lagrange Theorem](../../algebra/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn title_ref_without_title() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}");
+        let ref_input = String::from(r"{{tref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn quoted_title_ref() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/algebra/groups.md".into();
+        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange's Theorem]");
+        let ref_input = String::from(r"{{qtref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from(
+            "[Proposition 1.2.1, \"Lagrange's Theorem\"](../../algebra/groups.md#prop:lagrange)",
+        );
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn quoted_title_ref_without_title() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}");
+        let ref_input = String::from(r"{{qtref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &PATH,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &PATH, &refs);
+        let expected = String::from("[Proposition 1.2.1](#prop:lagrange)");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn highlight_target() {
+        let pre = NumThmPreprocessor {
+            highlight_target: true,
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{prop}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\" class=\"numthm-target\">\
+            **Proposition 1.2.1 (Lagrange Theorem).**</a> \
+            **Proposition 1.2.2.**",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn install_assets_twice() {
+        let dir = std::env::temp_dir().join(format!(
+            "mdbook-numthm-install-assets-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(install_assets(&dir).unwrap().len(), 2);
+        assert!(install_assets(&dir).unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("numthm.css")).unwrap(),
+            CSS
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counter_import_export() {
+        let dir =
+            std::env::temp_dir().join(format!("mdbook-numthm-counters-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            reset = "never"
+            counter_import = "previous.json"
+            counter_export = "counters.json"
+            "#,
+        );
+        ctx.root = dir.clone();
+        let pre = NumThmPreprocessor::new(&ctx);
+        // the first volume has no previous volume
+        let book = pre
+            .run(&ctx, test_book(&[("groups.md", "{{thm}}")]))
+            .unwrap();
+        assert_eq!(contents(&book), vec!["**Theorem 1.**"]);
+        // an unreadable import file is an error
+        std::fs::write(dir.join("previous.json"), "[").unwrap();
+        assert!(pre
+            .run(&ctx, test_book(&[("groups.md", "{{thm}}")]))
+            .is_err());
+        std::fs::write(dir.join("previous.json"), "{}").unwrap();
+        let book = test_book(&[("groups.md", "{{thm}} {{lem}} {{thm}} {{thm+}}")]);
+        pre.run(&ctx, book).unwrap();
+        let counters = std::fs::read_to_string(dir.join("counters.json")).unwrap();
+        assert_eq!(counters, "{\n  \"lem\": 1,\n  \"thm\": 2\n}");
+        // the second volume continues the numbering of the first one
+        std::fs::rename(dir.join("counters.json"), dir.join("previous.json")).unwrap();
+        let book = test_book(&[("fields.md", "{{thm}} {{lem}} {{def}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["**Theorem 3.** **Lemma 2.** **Definition 1.**"]
+        );
+        let counters = std::fs::read_to_string(dir.join("counters.json")).unwrap();
+        assert_eq!(counters, "{\n  \"def\": 1,\n  \"lem\": 2,\n  \"thm\": 3\n}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hypothesis() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let hyp = pre.envs.iter().find(|env| env.key == "hyp").unwrap();
+        let input = String::from(r"{{hyp}}{hyp:growth}[Growth condition] {{ref: hyp:growth}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, hyp, &mut HashMap::new(), &mut refs);
+        let output = pre.find_and_replace_refs(&output, &PATH, &refs);
+        let expected = String::from(
+            "<a id=\"hyp:growth\"></a>\n\
+            **(H1) (Growth condition).** \
+            [(H1)](#hyp:growth)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn hypothesis_numbered_throughout_book() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("intro.md", "{{hyp}} {{thm}}"),
+            (
+                "groups.md",
+                "{{hyp}}{hyp:finite} {{thm}} {{ref: hyp:finite}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**(H1).** **Theorem 1.1.**",
+                "<a id=\"hyp:finite\"></a>\n**(H2).** **Theorem 2.1.** [(H2)](#hyp:finite)",
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_environment_with_template() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              { key = "ass", name = "Assumption", template = "({name} A{n})" }
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{ass}} {{ass}}"), ("groups.md", "{{ass}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**(Assumption A1).** **(Assumption A2).**",
+                "**(Assumption A1).**",
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_region() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(
+            "{{prop}}{prop:lagrange}\n\
+            <!-- numthm:skip-start -->\n\
+            {{prop}}{prop:cauchy} {{ref: prop:lagrange}}\n\
+            <!-- numthm:skip-end -->\n\
+            {{prop}} {{ref: prop:lagrange}} {{ref: prop:cauchy}}",
+        );
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let output = pre.find_and_replace_refs(&output, &PATH, &refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition 1.2.1.**\n\
+            <!-- numthm:skip-start -->\n\
+            {{prop}}{prop:cauchy} {{ref: prop:lagrange}}\n\
+            <!-- numthm:skip-end -->\n\
+            **Proposition 1.2.2.** [Proposition 1.2.1](#prop:lagrange) **[??]**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs.len(), 1);
+    }
+
+    #[test]
+    fn unterminated_skip_region() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from("{{prop}}\n<!-- numthm:skip-start -->\n{{prop}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from("**Proposition 1.2.1.**\n<!-- numthm:skip-start -->\n{{prop}}");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn directive_at_file_start() {
+        let ctx = test_ctx("");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("intro.md", "{{thm}}{thm:main}[Main Theorem]\nSome text."),
+            ("groups.md", "{{lem}}\nSome text."),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:main\"></a>\n**Theorem 1 (Main Theorem).**\nSome text.",
+                "**Lemma 1.**\nSome text.",
+            ]
+        );
+    }
+
+    #[test]
+    fn directive_at_file_start_with_leading_newline() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            leading_newline = true
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("intro.md", "{{thm}}{thm:main}[Main Theorem]\nSome text."),
+            ("groups.md", "{{lem}}\nSome text."),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "\n<a id=\"thm:main\"></a>\n**Theorem 1 (Main Theorem).**\nSome text.",
+                "**Lemma 1.**\nSome text.",
+            ]
+        );
+    }
+
+    #[test]
+    fn trailing_punct() {
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/groups.md".into();
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"By {{ref: prop:lagrange}}, see {{tref: prop:lagrange}}.");
+        let pre = NumThmPreprocessor::default();
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from(
+            "By [Proposition 1.2.1](../math/groups.md#prop:lagrange), \
+            see [Lagrange Theorem](../math/groups.md#prop:lagrange).",
+        );
+        assert_eq!(ref_output, expected);
+
+        let pre = NumThmPreprocessor {
+            absorb_trailing_punct: true,
+            ..Default::default()
+        };
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from(
+            "By [Proposition 1.2.1,](../math/groups.md#prop:lagrange) \
+            see [Lagrange Theorem.](../math/groups.md#prop:lagrange)",
+        );
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn label_normalization() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            label_normalization = [
+              "trim",
+              "lowercase",
+              { replace = " ", with = "-" },
+              { strip_prefix = "my-" },
+              "unknown",
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}}{ My Lagrange Theorem } {{ref: lagrange theorem}}",
+            ),
+            (
+                "fields.md",
+                "{{ref:MY-LAGRANGE-THEOREM}} {{proof}}[of My Lagrange theorem] Done. {{proof-end}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"lagrange-theorem\"></a>\n**Theorem 1.** [Theorem 1](#lagrange-theorem)",
+                "[Theorem 1](groups.md#lagrange-theorem) <details class=\"numthm-proof\">\n\
+                <summary>Proof of <a href=\"groups.md#lagrange-theorem\">Theorem 1</a>.</summary>\n\n\
+                Done.\n\n</details>",
+            ]
+        );
+        assert_eq!(pre.label_normalization.len(), 4);
+
+        // distinct labels normalized to the same label are only warned about, except in strict mode
+        let chapters = [("groups.md", "{{thm}}{My Theorem} {{lem}}{my-theorem}")];
+        assert!(pre.run(&ctx, test_book(&chapters)).is_ok());
+        let pre = NumThmPreprocessor {
+            strict: true,
+            ..pre
+        };
+        let error = pre.run(&ctx, test_book(&chapters)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Labels `My Theorem' and `my-theorem' are both normalized to `theorem'"
+        );
+    }
+
+    #[test]
+    fn anchor_attribute() {
+        let input = String::from(r"{{thm}}{thm:a}");
+        for (attribute, expected) in [
+            (
+                AnchorAttribute::Id,
+                "<a id=\"thm:a\"></a>\n**Theorem 1.2.1.**",
+            ),
+            (
+                AnchorAttribute::Name,
+                "<a name=\"thm:a\"></a>\n**Theorem 1.2.1.**",
+            ),
+            (
+                AnchorAttribute::Both,
+                "<a id=\"thm:a\" name=\"thm:a\"></a>\n**Theorem 1.2.1.**",
+            ),
+        ] {
+            let pre = NumThmPreprocessor {
+                anchor_attribute: attribute,
+                ..Default::default()
+            };
+            let output = pre.find_and_replace_envs(
+                &input,
+                SECNUM,
+                &PATH,
+                &THM,
+                &mut HashMap::new(),
+                &mut HashMap::new(),
+            );
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn todo_unknown_refs() {
+        let pre = NumThmPreprocessor {
+            todo_unknown_refs: true,
+            ..Default::default()
+        };
+        let refs = HashMap::new();
+        let input = String::from(r"By {{ref: thm:missing}}, see {{tref: thm:other}}.");
+        let output = pre.find_and_replace_refs(&input, &PATH, &refs);
+        let expected = String::from(
+            "By **[??]**<!-- TODO: define label 'thm:missing' -->, \
+            see **[??]**<!-- TODO: define label 'thm:other' -->.",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn enabled_reftypes() {
+        let ctx =
+            test_ctx("[preprocessor.numthm]\nenabled_reftypes = [\"ref\", \"jsref\", \"xref\"]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert_eq!(pre.enabled_reftypes, vec!["ref", "jsref"]);
+        let book = test_book(&[(
+            "groups.md",
+            "{{thm}}{thm:a}[Lagrange] {{ref: thm:a}}, {{tref: thm:a}}, {{xref: thm:a}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1 (Lagrange).** \
+                [Theorem 1](#thm:a), {{tref: thm:a}}, {{xref: thm:a}}"
+            ]
+        );
+        let disabled: Vec<_> = any_ref_regex("")
+            .captures_iter("{{ref: thm:a}}, {{tref: thm:a}}, {{xref: thm:a}}")
+            .map(|caps| caps["reftype"].to_string())
+            .filter(|reftype| !pre.enabled_reftypes.contains(reftype))
+            .collect();
+        assert_eq!(disabled, vec!["tref", "xref"]);
+    }
+
+    #[test]
+    fn multiple_labels() {
+        let book = || {
+            test_book(&[(
+                "groups.md",
+                "{{thm}}{thm:a} {{lem}}{lem:b} {{lem}}{lem:c}\n\n\
+                {{ref: thm:a, lem:b}}. {{ref: thm:a,lem:b , lem:c}}. {{ref: thm:a, lem:missing, lem:c}}.",
+            )])
+        };
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let output = NumThmPreprocessor::new(&ctx).run(&ctx, book()).unwrap();
+        assert!(contents(&output)[0].ends_with(
+            "[Theorem 1](#thm:a) and [Lemma 1](#lem:b). \
+            [Theorem 1](#thm:a), [Lemma 1](#lem:b) and [Lemma 2](#lem:c). \
+            [Theorem 1](#thm:a), **[??]** and [Lemma 2](#lem:c)."
+        ));
+
+        let ctx = test_ctx(
+            "[preprocessor.numthm]\nref_join_last = \" or \"\nref_oxford_comma = true\nabsorb_trailing_punct = true",
+        );
+        let output = NumThmPreprocessor::new(&ctx).run(&ctx, book()).unwrap();
+        assert!(contents(&output)[0].ends_with(
+            "[Theorem 1](#thm:a) or [Lemma 1.](#lem:b) \
+            [Theorem 1](#thm:a), [Lemma 1](#lem:b), or [Lemma 2.](#lem:c) \
+            [Theorem 1](#thm:a), **[??]**, or [Lemma 2.](#lem:c)"
+        ));
+    }
+
+    #[test]
+    fn directive_syntax() {
+        let run = |config: &str, content: &str| {
+            let ctx = test_ctx(&format!("[preprocessor.numthm]\n{config}"));
+            let pre = NumThmPreprocessor::new(&ctx);
+            let mut refs = HashMap::new();
+            let output = pre.find_and_replace_all_envs(
+                content,
+                SECNUM,
+                &PATH,
+                &pre.envs,
+                &mut HashMap::new(),
+                &mut refs,
+                &mut ProofLinks::default(),
+            );
+            let output = pre.find_and_replace_block_envs(&output, &PATH, &refs);
+            (output, refs)
+        };
+        let (brackets, bracket_refs) = run(
+            "",
+            "{{thm}}{thm:a}[Lagrange] {{lem*}}{lem:b} {{thm}}[Title]{status=open} {{proof}}[of thm:a] Done. {{proof-end}}",
+        );
+        let (pipes, pipe_refs) = run(
+            "directive_syntax = \"pipes\"",
+            "{{thm | thm:a | Lagrange}} {{lem* |lem:b}} {{thm | | Title}}{status=open} {{proof||of thm:a}} Done. {{proof-end}}",
+        );
+        assert_eq!(pipes, brackets);
+        assert_eq!(pipe_refs, bracket_refs);
+        assert_eq!(pipe_refs["thm:a"].title.as_deref(), Some("Lagrange"));
+        // the bracket syntax is not recognized with the pipe syntax
+        let (output, _) = run("directive_syntax = \"pipes\"", "{{thm}}{thm:a}");
+        assert_eq!(output, "**Theorem 1.2.1.**{thm:a}");
+    }
+
+    #[test]
+    fn equations() {
+        let chapters = || {
+            test_book(&[
+                (
+                    "groups.md",
+                    "{{thm}}\n\n$$\na \\leq b\n$$\n{{eq}}{eq:bound}\n\n$$\nb = c\n$$\n{{eq}}",
+                ),
+                ("fields.md", "By {{eqref: eq:bound}} and {{ref: eq:bound}}."),
+            ])
+        };
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = pre.run(&ctx, chapters()).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.**\n\n$$\na \\leq b\n$$\n<a id=\"eq:bound\"></a>\n\
+                <span class=\"numthm-eq-tag\">(1.1)</span>\n\n$$\nb = c\n$$\n\
+                <span class=\"numthm-eq-tag\">(1.2)</span>",
+                "By [(1.1)](groups.md#eq:bound) and [Equation 1.1](groups.md#eq:bound).",
+            ]
+        );
+        // the default tag is plain text for renderers other than HTML
+        let mut ctx = test_ctx("[preprocessor.numthm]\nprefix = true");
+        ctx.renderer = "markdown".to_string();
+        let book = pre.run(&ctx, chapters()).unwrap();
+        assert!(contents(&book)[0].ends_with("</a>\n(1.1)\n\n$$\nb = c\n$$\n(1.2)"));
+        let ctx = test_ctx("[preprocessor.numthm]\neq_tag = \"\\\\(({num})\\\\)\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = pre.run(&ctx, chapters()).unwrap();
+        assert!(contents(&book)[0].ends_with("$$\nb = c\n$$\n\\((2)\\)"));
+    }
+
+    #[test]
+    fn subequations() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\neq_tag = \"({num})\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "$$\na\n$$\n{{eq}}\n\n{{subeq-begin}}{eq:group}\n$$\nb\n$$\n{{eq}}\n\n$$\nc\n$$\n{{eq}}{eq:c}\n\
+                {{subeq-end}}\n\n$$\nd\n$$\n{{eq}}\n\n`{{subeq-begin}}`\n{{subeq-begin}}\n{{eq}}",
+            ),
+            (
+                "fields.md",
+                "{{eq}} {{subeq-end}} {{eq}} By {{eqref: eq:group}} and {{eqref: eq:c}}.",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "$$\na\n$$\n(1.1)\n\n<a id=\"eq:group\"></a>\n$$\nb\n$$\n(1.2a)\n\n$$\nc\n$$\n\
+                <a id=\"eq:c\"></a>\n(1.2b)\n\n\n$$\nd\n$$\n(1.3)\n\n`{{subeq-begin}}`\n\n(1.4a)",
+                // unterminated groups end with the chapter, and unmatched ends are ignored
+                "(2.1)  (2.2) By [(1.2)](groups.md#eq:group) and [(1.2b)](groups.md#eq:c).",
+            ]
+        );
+        // nested groups are ignored
+        let book = test_book(&[(
+            "groups.md",
+            "{{subeq-begin}} {{eq}} {{subeq-begin}} {{eq}} {{subeq-end}} {{eq}} {{subeq-end}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(contents(&book), vec![" (1.1a)  (1.1b)  (1.2) "]);
+    }
+
+    #[test]
+    fn ref_template() {
+        let ctx = test_ctx("[preprocessor.numthm]\nref_template = \"see {text} above\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("groups.md", "{{thm}}{thm:a}[Lagrange]"),
+            ("fields.md", "By {{ref: thm:a}}, {{tref: thm:a}}."),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "By [see Theorem 1 above](groups.md#thm:a), [see Lagrange above](groups.md#thm:a)."
+        );
+        // templates without placeholder are ignored
+        let ctx = test_ctx("[preprocessor.numthm]\nref_template = \"see\"");
+        assert_eq!(NumThmPreprocessor::new(&ctx).ref_template, None);
+    }
+
+    #[test]
+    fn short_refs() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:a} {{prop*}}{prop:b}");
+        let _output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let input = String::from(r"By ({{sref: prop:a}}) and {{sref: prop:b}}.");
+        assert_eq!(
+            pre.find_and_replace_refs(&input, &PATH, &refs),
+            "By ([1.2.1](#prop:a)) and [Proposition](#prop:b)."
+        );
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        assert_eq!(
+            pre.find_and_replace_refs(&input, &ref_file, &refs),
+            "By ([1.2.1](groups.md#prop:a)) and [Proposition](groups.md#prop:b)."
+        );
+    }
+
+    #[test]
+    fn parenthetical_refs() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{prop}}{prop:a}");
+        let _output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let input = String::from(r"Groups are nice {{pref: prop:a}}.");
+        assert_eq!(
+            pre.find_and_replace_refs(&input, &PATH, &refs),
+            "Groups are nice [(Proposition 1.2.1)](#prop:a)."
+        );
+        let ref_file: PathBuf = "math/fields.md".into();
+        assert_eq!(
+            pre.find_and_replace_refs(&input, &ref_file, &refs),
+            "Groups are nice [(Proposition 1.2.1)](../crypto/groups.md#prop:a)."
+        );
+    }
+
+    #[test]
+    fn plurals() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              { key = "hyp", name = "Hypothesis", plural = "Hypotheses" },
+              { key = "claim", inherits = "hyp", name = "Claim" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "groups.md",
+            "{{lem}}{lem:a} {{lem}}{lem:b} {{lem}}{lem:c} {{hyp}}{hyp:a} {{hyp}}{hyp:b} {{claim}}{claim:a} \
+            {{claim}}{claim:b}\n\n\
+            {{ref: lem:b, lem:c}}. {{ref: lem:a, lem:b, lem:c}}. {{ref: hyp:a, hyp:b}}. \
+            {{ref: claim:a--claim:b}}. {{ref: lem:a, hyp:b}}. {{tref: lem:a, lem:b}}.",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert!(contents(&book)[0].ends_with(
+            "Lemmas [2](#lem:b) and [3](#lem:c). Lemmas [1](#lem:a), [2](#lem:b) and [3](#lem:c). \
+            Hypotheses [1](#hyp:a) and [2](#hyp:b). Claims [1](#claim:a)–[2](#claim:b). \
+            [Lemma 1](#lem:a) and [Hypothesis 2](#hyp:b). [Lemma 1](#lem:a) and [Lemma 2](#lem:b)."
+        ));
+    }
+
+    #[test]
+    fn ref_ranges() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}}{thm:a} {{thm}}{thm:b} {{thm}}{thm:c} {{lem}}{lem:d}",
+            ),
+            (
+                "fields.md",
+                "{{ref: thm:a--thm:c}}. {{ref: thm:a -- lem:d}}. {{ref: thm:a--thm:missing}}.",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "Theorems [1](groups.md#thm:a)–[3](groups.md#thm:c). \
+            [Theorem 1](groups.md#thm:a)–[Lemma 1](groups.md#lem:d). \
+            [Theorem 1](groups.md#thm:a)–**[??]**."
+        );
+    }
+
+    #[test]
+    fn missing_ref_text() {
+        let ctx = test_ctx(
+            "[preprocessor.numthm]\nmissing_ref_text = \"<span class=\\\"missing\\\">{label}?</span>\"",
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "groups.md",
+            "{{thm}}{thm:a} {{ref: thm:a}}, {{ref: thm:b}}.",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** [Theorem 1](#thm:a), \
+                <span class=\"missing\">thm:b?</span>."
+            ]
+        );
+    }
+
+    #[test]
+    fn js_ref() {
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/groups.md".into();
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{jsref: prop:lagrange}}, {{jsref: prop:unknown}}");
+        let pre = NumThmPreprocessor::default();
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from(
+            "<a href=\"../math/groups.md#prop:lagrange\" class=\"numthm-jsref\" \
+            data-label=\"prop:lagrange\"></a>, **[??]**",
+        );
+        assert_eq!(ref_output, expected);
+
+        // without HTML output, the reference falls back to a regular link
+        let pre = NumThmPreprocessor {
+            html_renderer: false,
+            ..Default::default()
+        };
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected =
+            String::from("[Proposition 1.2.1](../math/groups.md#prop:lagrange), **[??]**");
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn print_pagerefs() {
+        let pre = NumThmPreprocessor {
+            print_pagerefs: true,
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/groups.md".into();
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
+        let ref_input = String::from(r"{{ref: prop:lagrange}} {{tref: prop:lagrange}}");
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from(
+            "[Proposition 1.2.1](../math/groups.md#prop:lagrange) (page ⟨pageref:prop:lagrange⟩) \
+            [Lagrange Theorem](../math/groups.md#prop:lagrange) (page ⟨pageref:prop:lagrange⟩)",
+        );
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn citation_mode() {
+        let pre = NumThmPreprocessor {
+            citation_mode: true,
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let label_file: PathBuf = "math/groups.md".into();
+        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
+        let label_input =
+            String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{prop}}{prop:cauchy}");
+        let ref_input = String::from(
+            r"By {{ref: prop:cauchy}}, {{tref: prop:lagrange}} {{ref: prop:lagrange}} and {{ref: prop:cauchy}}.",
+        );
+        let _label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        let ref_output = pre.find_and_replace_refs(&ref_input, &ref_file, &refs);
+        let expected = String::from(
+            "By [\\[1\\]](#numthm-cite-1), [Lagrange Theorem](../math/groups.md#prop:lagrange) \
+            [\\[2\\]](#numthm-cite-2) and [\\[1\\]](#numthm-cite-1).\n\n\
+            **References**\n\n\
+            - <a id=\"numthm-cite-1\"></a>\\[1\\] [Proposition 1.2.2](../math/groups.md#prop:cauchy)\n\
+            - <a id=\"numthm-cite-2\"></a>\\[2\\] [Proposition 1.2.1](../math/groups.md#prop:lagrange)\n",
+        );
+        assert_eq!(ref_output, expected);
+    }
+
+    #[test]
+    fn proof_blocks() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r"{{thm}}{thm:foo} Every group is a monoid.");
+        let _output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &THM, &mut HashMap::new(), &mut refs);
+        let input = String::from(
+            "{{proof}}\nObvious.\n{{proof-end}}\n\n\
+            {{proof}}{proof:foo}[of thm:foo]\nBy {{ref: thm:foo}}.\n\n\
+            {{proof}}[Sketch]\nNested.\n{{proof-end}}\n{{proof-end}}",
+        );
+        let output = pre.find_and_replace_block_envs(&input, &PATH, &refs);
+        let expected = String::from(
+            "<details class=\"numthm-proof\">\n<summary>Proof.</summary>\n\nObvious.\n\n</details>\n\n\
+            <a id=\"proof:foo\"></a>\n<details class=\"numthm-proof\">\n\
+            <summary>Proof of <a href=\"#thm:foo\">Theorem 1.2.1</a>.</summary>\n\nBy {{ref: thm:foo}}.\n\n\
+            <details class=\"numthm-proof\">\n<summary>Proof (Sketch).</summary>\n\nNested.\n\n</details>\n\n</details>",
+        );
+        assert_eq!(output, expected);
+        let pre = NumThmPreprocessor {
+            collapsible_proof: false,
+            ..Default::default()
+        };
+        let output = pre.find_and_replace_block_envs(&input, &PATH, &refs);
+        let expected = String::from(
+            "*Proof.* Obvious.\n\n\
+            <a id=\"proof:foo\"></a>\n*Proof of [Theorem 1.2.1](#thm:foo).* By {{ref: thm:foo}}.\n\n\
+            *Proof (Sketch).* Nested.",
+        );
+        assert_eq!(output, expected);
+        // markers in code regions are ignored, both outside and inside proof blocks
+        let input = String::from(
+            "```\n{{proof}}\n```\n`{{proof-end}}` \\{{proof}}\n\
+            {{proof}}\nWrite\n```\n{{end:proof}}\n```\nor `{{proof}}`.\n{{end:proof}}",
+        );
+        let output = pre.find_and_replace_block_envs(&input, &PATH, &refs);
+        let expected = String::from(
+            "```\n{{proof}}\n```\n`{{proof-end}}` \\{{proof}}\n\
+            *Proof.* Write\n```\n{{end:proof}}\n```\nor `{{proof}}`.",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn link_proofs() {
+        let ctx = test_ctx("[preprocessor.numthm]\nlink_proofs = true\ncollapsible_proof = false");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}}{thm:a} Statement.\n\n{{proof}}{proof:a}\nObvious.\n{{proof-end}}\n\n\
+                {{lem}}{lem:b} Statement.\n\n{{proof}}[Sketch]\nEasy.\n{{proof-end}}\n\n\
+                {{thm}} Statement.\n\n{{proof}}\nTrivial.\n{{proof-end}}",
+            ),
+            (
+                "fields.md",
+                "{{proof}}\nOrphan.\n{{proof-end}}\n\n\
+                {{thm}}{thm:c} Statement.\n\n```\ncode\n```\n\n{{proof}}\nAdjacent.\n{{proof-end}}\n\n\
+                {{thm}}{thm:d} Statement.\n\n{{lem}} Statement.\n\n{{proof}}\nOf the lemma.\n{{proof-end}}\n\n\
+                {{proof}}[of thm:d]\nDeferred.\n{{proof-end}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** Statement.\n\n\
+                <a id=\"proof:a\"></a>\n*Proof of [Theorem 1](#thm:a).* Obvious.\n\n\
+                <a id=\"lem:b\"></a>\n**Lemma 1.** Statement.\n\n*Proof (Sketch).* Easy.\n\n\
+                **Theorem 2.** Statement.\n\n*Proof of Theorem 2.* Trivial.",
+                // orphan proofs are left as is, proofs are linked across code blocks,
+                // and environments link their deferred proofs
+                "*Proof.* Orphan.\n\n\
+                <a id=\"thm:c\"></a>\n**Theorem 1.** Statement.\n\n```\ncode\n```\n\n\
+                *Proof of [Theorem 1](#thm:c).* Adjacent.\n\n\
+                <a id=\"thm:d\"></a>\n**Theorem 2.** [[Proof ↓]](#proof-of-thm:d) Statement.\n\n\
+                **Lemma 1.** Statement.\n\n*Proof of Lemma 1.* Of the lemma.\n\n\
+                <a id=\"proof-of-thm:d\"></a>\n*Proof of [Theorem 2](#thm:d).* Deferred.",
+            ]
+        );
+        // with the pipe syntax
+        let ctx = test_ctx(
+            "[preprocessor.numthm]\nlink_proofs = true\ncollapsible_proof = false\ndirective_syntax = \"pipes\"",
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "groups.md",
+            "{{thm | thm:a}} {{lem}} {{proof}}\nA.\n{{proof-end}} {{proof | | of thm:a}}\nB.\n{{proof-end}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** [[Proof ↓]](#proof-of-thm:a) **Lemma 1.** \
+                *Proof of Lemma 1.* A. <a id=\"proof-of-thm:a\"></a>\n*Proof of [Theorem 1](#thm:a).* B."
+            ]
+        );
+    }
+
+    #[test]
+    fn code_regions() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(
+            "Use `{{thm}}` or ``{{thm}}{thm:a}`` and `` ` `` to write {{thm}}{thm:b}.\n\
+            ```markdown\n{{thm}}\n{{ref: thm:b}}\n```\n\
+            {{ref: thm:b}}\n\
+            ~~~~\n{{lem}}\n~~~\n{{lem}}\n~~~~\n\
+            Unmatched ``` `{{thm}}",
+        );
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &THM, &mut HashMap::new(), &mut refs);
+        let output = pre.find_and_replace_refs(&output, &PATH, &refs);
+        let expected = String::from(
+            "Use `{{thm}}` or ``{{thm}}{thm:a}`` and `` ` `` to write <a id=\"thm:b\"></a>\n**Theorem 1.2.1.**.\n\
+            ```markdown\n{{thm}}\n{{ref: thm:b}}\n```\n\
+            [Theorem 1.2.1](#thm:b)\n\
+            ~~~~\n{{lem}}\n~~~\n{{lem}}\n~~~~\n\
+            Unmatched ``` `**Theorem 1.2.2.**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(
+            split_code_regions("a `b` c\n```\nd"),
+            vec![
+                (false, "a "),
+                (true, "`b`"),
+                (false, " c\n"),
+                (true, "```\nd")
+            ]
+        );
+    }
+
+    #[test]
+    fn fenced_code_blocks() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "syntax.md",
+            "Write\n\n```markdown\n{{thm}}{thm:a} Statement.\n```\n\nto get\n\n{{thm}}{thm:a} Statement.\n\n\
+            ~~~\n{{ref: thm:a}}\n~~~\n\nwhich gives {{ref: thm:a}}.",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "Write\n\n```markdown\n{{thm}}{thm:a} Statement.\n```\n\nto get\n\n\
+                <a id=\"thm:a\"></a>\n**Theorem 1.** Statement.\n\n\
+                ~~~\n{{ref: thm:a}}\n~~~\n\nwhich gives [Theorem 1](#thm:a)."
+            ]
+        );
+    }
+
+    #[test]
+    fn html_comments() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "drafts.md",
+            "<!-- {{thm}}{thm:foo} This is work in progress -->\n{{thm}} <!-- {{ref: thm:foo}} -->\n\
+            <!--\n```\n{{lem}}\n-->\n{{lem}}\n```\n<!-- {{lem}} -->\n```\n<!-- {{thm}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<!-- {{thm}}{thm:foo} This is work in progress -->\n**Theorem 1.** <!-- {{ref: thm:foo}} -->\n\
+                <!--\n```\n{{lem}}\n-->\n**Lemma 1.**\n```\n<!-- {{lem}} -->\n```\n<!-- {{thm}}"
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_directives() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "syntax.md",
+            r"Write \{{thm}}{thm:a} and \{{ref: thm:a}} to get \\{{thm}}{thm:a} and {{ref: thm:a}}, \\\{{thm}}. `\{{thm}}`",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<!-- numthm:processed-start -->\n\
+                Write {{thm}}{thm:a} and {{ref: thm:a}} to get \\\\<a id=\"thm:a\"></a>\n**Theorem 1.** and \
+                [Theorem 1](#thm:a), \\\\{{thm}}. `\\{{thm}}`\n\
+                <!-- numthm:processed-end -->"
+            ]
+        );
+    }
+    #[test]
+    fn directives_in_code() {
+        let directives = [
+            "{{thm}}{thm:a}[Title]",
+            "{{thm*}}",
+            "{{ref: thm:a}}",
+            "{{tref: thm:a}}",
+            "{{ref: thm:a, thm:a}}",
+            "{{eqref: eq:a}}",
+            "{{listof: thm}}",
+            "{{open-problems}}",
+            "{{set: thm=4}}",
+            "{{appendix}}",
+            "{{eq}}{eq:a}",
+            "{{proof}}\nObvious.\n{{proof-end}}",
+            "{{proof}}\nObvious.\n{{end:proof}}",
+        ];
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let defined = "<a id=\"thm:a\"></a>\n**Theorem 1.1.**";
+        for directive in directives {
+            let mut cases = vec![
+                (format!("```\n{directive}\n```"), None),
+                (format!("~~~markdown\n{directive}\n~~~"), None),
+                (format!("<!-- {directive} -->"), None),
+                (directive.replace("{{", r"\{{"), Some(directive.to_string())),
+            ];
+            if !directive.contains('\n') {
+                cases.push((format!("`{directive}`"), None));
+            }
+            for (untouched, expected) in cases {
+                let input = format!("{{{{thm}}}}{{thm:a}}\n\n{untouched}\n\n{{{{thm}}}}");
+                let book = pre.run(&ctx, test_book(&[("intro.md", &input)])).unwrap();
+                let output = match expected {
+                    // unescaped directives are marked to be left untouched if processed again
+                    Some(expected) => format!(
+                        "{PROCESSED_START}\n{defined}\n\n{expected}\n\n**Theorem 1.2.**\n{PROCESSED_END}"
+                    ),
+                    None => format!("{defined}\n\n{untouched}\n\n**Theorem 1.2.**"),
+                };
+                assert_eq!(contents(&book), vec![output], "{input}");
+            }
+        }
+    }
+
+    #[test]
+    fn supports_renderer() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert!(pre.supports_renderer("html"));
+        assert!(!pre.supports_renderer("linkcheck"));
+        let ctx = test_ctx("[preprocessor.numthm]\nrenderers = [\"html\", \"markdown\"]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert!(pre.supports_renderer("markdown"));
+        assert!(!pre.supports_renderer("linkcheck"));
+    }
+
+    #[test]
+    fn counter_group() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              ["thm", "Theorem", "**", "main_thms"],
+              ["lem", "Lemma", "**", "main_thms"],
+              ["prop", "Proposition", "**", 3, "main_thms"],
+              { key = "corr", name = "Corollary", counter_group = "main_thms" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}} {{lem}} {{def}} {{prop}}{prop:a} {{lem}} {{rem}} {{corr}} {{thm}} {{def}} {{ref: prop:a}}",
+            ),
+            ("fields.md", "{{lem}} {{thm}} {{rem}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** **Lemma 2.** **Definition 1.** <a id=\"prop:a\"></a>\n**Proposition 3.** \
+                **Lemma 4.** *Remark 1.* **Corollary 5.** **Theorem 6.** **Definition 2.** [Proposition 3](#prop:a)",
+                "**Lemma 1.** **Theorem 2.** *Remark 1.*",
+            ]
+        );
+    }
+
+    #[test]
+    fn explicit_counter_group() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              ["ax", "Axiom", "**", "counter=roman"],
+              ["post", "Postulate", "**", "roman", "counter=roman"],
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let ax = pre.envs.iter().find(|env| env.key == "ax").unwrap();
+        assert_eq!(ax.counter_name(), "roman");
+        assert!(ax.numbering_style.is_none());
+        let post = pre.envs.iter().find(|env| env.key == "post").unwrap();
+        assert_eq!(post.counter_name(), "roman");
+        assert!(post.numbering_style.is_some());
+    }
+
+    #[test]
+    fn numbered_like() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            shared_counter = ["thm", "lem"]
+            custom_environments = [
+              { key = "fact", name = "Fact", numbered_like = "claim" },
+              { key = "claim", name = "Claim", numbered_like = "lem" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let claim = pre.envs.iter().find(|env| env.key == "claim").unwrap();
+        assert_eq!(claim.counter_name(), "thm");
+        let fact = pre.envs.iter().find(|env| env.key == "fact").unwrap();
+        assert_eq!(fact.counter_name(), "thm");
+        let book = test_book(&[(
+            "groups.md",
+            "{{thm}} {{claim}}{claim:a} {{lem}} {{claim}} {{fact}} {{ref: claim:a}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** <a id=\"claim:a\"></a>\n**Claim 2.** **Lemma 3.** **Claim 4.** **Fact 5.** \
+                [Claim 2](#claim:a)"
+            ]
+        );
+
+        // unknown targets and cycles are configuration errors
+        for (environments, error) in [
+            (
+                r#"{ key = "fact", name = "Fact", numbered_like = "unknown" }"#,
+                "Unknown environment `unknown' in `numbered_like' of environment `fact'",
+            ),
+            (
+                r#"{ key = "a", name = "A", numbered_like = "b" }, { key = "b", name = "B", numbered_like = "c" }"#,
+                "Unknown environment `c' in `numbered_like' of environment `b'",
+            ),
+            (
+                r#"{ key = "a", name = "A", numbered_like = "b" }, { key = "b", name = "B", numbered_like = "a" }"#,
+                "Cycle in `numbered_like': a -> b -> a",
+            ),
+        ] {
+            let ctx = test_ctx(&format!(
+                "[preprocessor.numthm]\ncustom_environments = [{environments}]"
+            ));
+            let pre = NumThmPreprocessor::new(&ctx);
+            let err = pre
+                .run(&ctx, test_book(&[("groups.md", "{{thm}}")]))
+                .unwrap_err();
+            assert_eq!(err.to_string(), error);
+        }
+    }
+
+    #[test]
+    fn separator() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            separator = "-"
+            custom_environments = [{ key = "corr", name = "Corollary", within = "thm" }]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[
+            ("groups.md", "{{thm}}{thm:a} {{corr}}{corr:b}"),
+            ("fields.md", "{{thm}} {{ref: thm:a}} {{ref: corr:b}}"),
+        ]);
+        if let BookItem::Chapter(chapter) = &mut book.sections[1] {
+            chapter.number = Some(SectionNumber(vec![3, 2]));
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1-1.** <a id=\"corr:b\"></a>\n**Corollary 1-1-1.**",
+                "**Theorem 3-2-1.** [Theorem 1-1](groups.md#thm:a) [Corollary 1-1-1](groups.md#corr:b)",
+            ]
+        );
+        // the separator may be empty
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nseparator = \"\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = pre
+            .run(
+                &ctx,
+                test_book(&[("groups.md", "{{thm}}{thm:a} {{ref: thm:a}}")]),
+            )
+            .unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["<a id=\"thm:a\"></a>\n**Theorem 11.** [Theorem 11](#thm:a)"]
+        );
+    }
+
+    #[test]
+    fn prefix_separator() {
+        let run = |config: &str| {
+            let ctx = test_ctx(&format!("[preprocessor.numthm]\n{config}"));
+            let pre = NumThmPreprocessor::new(&ctx);
+            let mut book = test_book(&[("groups.md", "{{thm}}{thm:a} {{ref: thm:a}}")]);
+            if let BookItem::Chapter(chapter) = &mut book.sections[0] {
+                chapter.number = Some(SectionNumber(vec![1, 2]));
+            }
+            contents(&pre.run(&ctx, book).unwrap()).remove(0)
+        };
+        let expected =
+            |num: &str| format!("<a id=\"thm:a\"></a>\n**Theorem {num}.** [Theorem {num}](#thm:a)");
+        assert_eq!(run("prefix = true"), expected("1.2.1"));
+        assert_eq!(
+            run("prefix = true\nprefix_separator = \".\""),
+            expected("1.2.1")
+        );
+        assert_eq!(
+            run("prefix = true\nprefix_separator = \"-\""),
+            expected("1.2-1")
+        );
+        assert_eq!(
+            run("prefix = true\nprefix_separator = \"\""),
+            expected("1.21")
+        );
+        assert_eq!(
+            run("prefix = true\nseparator = \"-\"\nprefix_separator = \":\""),
+            expected("1-2:1")
+        );
+        // no separator without prefix
+        assert_eq!(run("prefix_separator = \"-\""), expected("1"));
+    }
+
+    #[test]
+    fn starred_envs() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(
+            "{{thm*}}[Zorn] {{thm}} {{thm*}}{thm:zorn} {{thm}}{thm:b}[Title] {{thm*}}{thm:c}[Choice]",
+        );
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &THM, &mut HashMap::new(), &mut refs);
+        assert_eq!(
+            output,
+            "**Theorem (Zorn).** **Theorem 1.2.1.** <a id=\"thm:zorn\"></a>\n**Theorem.** \
+            <a id=\"thm:b\"></a>\n**Theorem 1.2.2 (Title).** <a id=\"thm:c\"></a>\n**Theorem (Choice).**"
+        );
+        assert_eq!(refs["thm:zorn"].num_name, "Theorem");
+        assert_eq!(refs["thm:b"].num_name, "Theorem 1.2.2");
+        assert_eq!(refs["thm:c"].num_name, "Theorem");
+        assert_eq!(refs["thm:c"].title.as_deref(), Some("Choice"));
+        // starred variants can be disabled
+        let pre = NumThmPreprocessor {
+            starred_envs: false,
+            ..Default::default()
+        };
+        let output = pre.find_and_replace_envs(
+            "{{thm*}}{thm:a} {{thm}}",
+            SECNUM,
+            &PATH,
+            &THM,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        assert_eq!(output, "{{thm*}}{thm:a} **Theorem 1.2.1.**");
+    }
+
+    #[test]
+    fn fixed_number() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(
+            "{{thm}} {{thm}}{thm:h}[Hartshorne]{number=17.3} {{thm}}{thm:b} {{thm}}{number=\"\"}\n\n\
+            {{ref: thm:h}}",
+        );
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &THM, &mut HashMap::new(), &mut refs);
+        assert_eq!(
+            output,
+            "**Theorem 1.2.1.** <a id=\"thm:h\"></a>\n**Theorem 17.3 (Hartshorne).** \
+            <a id=\"thm:b\"></a>\n**Theorem 1.2.2.** **Theorem 1.2.3.**\n\n{{ref: thm:h}}"
+        );
+        assert_eq!(refs["thm:h"].num_name, "Theorem 17.3");
+        assert_eq!(refs["thm:b"].num_name, "Theorem 1.2.2");
+        let output = pre.find_and_replace_refs("{{ref: thm:h}}", &PATH, &refs);
+        assert_eq!(output, "[Theorem 17.3](#thm:h)");
+    }
+
+    #[test]
+    fn starred_envs_references() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}}{thm:a} {{thm*}}{thm:zorn}[Zorn] {{thm}}{thm:b}",
+            ),
+            (
+                "fields.md",
+                "{{ref: thm:a}}, {{ref: thm:zorn}}, {{tref: thm:zorn}}, {{ref: thm:b}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** <a id=\"thm:zorn\"></a>\n**Theorem (Zorn).** \
+                <a id=\"thm:b\"></a>\n**Theorem 2.**",
+                "[Theorem 1](groups.md#thm:a), [Theorem](groups.md#thm:zorn), \
+                [Zorn](groups.md#thm:zorn), [Theorem 2](groups.md#thm:b)",
+            ]
+        );
+    }
+
+    #[test]
+    fn sub_numbering() {
+        let ctx = test_ctx("[preprocessor.numthm]\nnumbering = \"continuous\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}} {{thm+}}{thm:a} {{thm+}} {{thm}} {{lem}} {{lem+}} {{ref: thm:a}}",
+            ),
+            ("fields.md", "{{thm+}} {{thm+}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** <a id=\"thm:a\"></a>\n**Theorem 1a.** **Theorem 1b.** **Theorem 2.** \
+                **Lemma 1.** **Lemma 1a.** [Theorem 1a](#thm:a)",
+                "**Theorem 3.** **Theorem 3a.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn set_counters() {
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}} {{set: thm=4 lem=2}}{{thm}}{thm:a} {{lem}} {{ref: thm:a}}",
+            ),
+            (
+                "fields.md",
+                "{{set: thm=-1 foo=3 lem}}{{thm}} {{set: thm=99999999999999999999999}}{{thm}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** <a id=\"thm:a\"></a>\n**Theorem 5.** **Lemma 3.** [Theorem 5](#thm:a)",
+                "**Theorem 1.** **Theorem 2.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn status_badge() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let input = String::from(r#"{{prop}}{prop:goldbach}[Goldbach]{status="open"} {{prop}}"#);
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        let expected = String::from(
+            "<a id=\"prop:goldbach\"></a>\n\
+            **Proposition 1.2.1 (Goldbach).** \
+            <span class=\"numthm-status numthm-status-open\">open</span> \
+            **Proposition 1.2.2.**",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(refs["prop:goldbach"].status, Some("open".to_string()));
+    }
+
+    #[test]
+    fn open_problems_registry() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [["conj", "Conjecture", "*"]]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "nt/primes.md",
+                "{{conj}}{conj:goldbach}[Goldbach]{status=open}\n\
+                {{conj}}{conj:twin}{status=partial}\n\
+                {{conj}}{conj:fermat}[Fermat]{status=solved}\n\
+                {{conj}}{conj:catalan}[Catalan]{status=open}\n\
+                {{conj}}{conj:none}",
+            ),
+            (
+                "problems.md",
+                "{{open-problems}}\n\nUse `{{open-problems}}`.",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "**Open** (2)\n\n\
+            - [Conjecture 1 (Goldbach)](nt/primes.md#conj:goldbach)\n\
+            - [Conjecture 4 (Catalan)](nt/primes.md#conj:catalan)\n\n\
+            **Partially solved** (1)\n\n\
+            - [Conjecture 2](nt/primes.md#conj:twin)\n\n\
+            **Solved** (1)\n\n\
+            - [Conjecture 3 (Fermat)](nt/primes.md#conj:fermat)\n\n\
+            Use `{{open-problems}}`."
+        );
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(
+            parse_attributes(r#"status="open"  tags=a,b"#),
+            vec![
+                ("status".to_string(), "open".to_string()),
+                ("tags".to_string(), "a,b".to_string())
+            ]
+        );
+    }
+
+    /// Processes a book with theorems whose titles are not in order of appearance
+    /// and returns the list of theorems sorted according to `index_sort`.
+    fn list_of_theorems(index_sort: &str) -> String {
+        let ctx = test_ctx(&format!(
+            r#"
+            [preprocessor.numthm]
+            index_sort = "{index_sort}"
+            "#
+        ));
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "intro.md",
+                "{{thm}}{thm:zorn}[Zorn's Lemma]\n\
+                {{lem}}{lem:aux}\n\
+                {{thm}}{thm:cantor}[cantor's Theorem]",
+            ),
+            (
+                "groups.md",
+                "{{thm}}{thm:untitled}\n\
+                {{thm}}{thm:lagrange}[Lagrange's Theorem]",
+            ),
+            ("index.md", "{{listof: thm}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        contents(&book)[2].clone()
+    }
+
+    #[test]
+    fn list_of_theorems_by_appearance() {
+        assert_eq!(
+            list_of_theorems("appearance"),
+            "- [Theorem 1 (Zorn's Lemma)](intro.md#thm:zorn)\n\
+            - [Theorem 2 (cantor's Theorem)](intro.md#thm:cantor)\n\
+            - [Theorem 1](groups.md#thm:untitled)\n\
+            - [Theorem 2 (Lagrange's Theorem)](groups.md#thm:lagrange)"
+        );
+    }
+
+    #[test]
+    fn list_of_tagged_theorems() {
+        let ctx = test_ctx("");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{thm}}{thm:a}[A]{tags=important,exam} {{thm}}{thm:b}{tags=exam} {{lem}}{lem:c}{tags=exam} \
+            {{thm}}{thm:d}{tags=important}\n\n\
+            {{listof: thm tag=exam}}\n\n{{listof: tag=exam tag=important}}\n\n`{{listof: thm}}`",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        let content = &contents(&book)[0];
+        assert!(content.ends_with(
+            "- [Theorem 1 (A)](#thm:a)\n\
+            - [Theorem 2](#thm:b)\n\n\
+            - [Theorem 1 (A)](#thm:a)\n\n`{{listof: thm}}`"
+        ));
+    }
+
+    #[test]
+    fn list_of_theorems_by_title() {
+        assert_eq!(
+            list_of_theorems("title"),
+            "- [Theorem 2 (cantor's Theorem)](intro.md#thm:cantor)\n\
+            - [Theorem 2 (Lagrange's Theorem)](groups.md#thm:lagrange)\n\
+            - [Theorem 1](groups.md#thm:untitled)\n\
+            - [Theorem 1 (Zorn's Lemma)](intro.md#thm:zorn)"
+        );
+    }
+
+    #[test]
+    fn list_of_theorems_by_number() {
+        assert_eq!(
+            list_of_theorems("number"),
+            "- [Theorem 1 (Zorn's Lemma)](intro.md#thm:zorn)\n\
+            - [Theorem 1](groups.md#thm:untitled)\n\
+            - [Theorem 2 (cantor's Theorem)](intro.md#thm:cantor)\n\
+            - [Theorem 2 (Lagrange's Theorem)](groups.md#thm:lagrange)"
+        );
+    }
+
+    /// Returns a preprocessor with the label prefix lint enabled.
+    fn label_lint_pre(strict: bool) -> NumThmPreprocessor {
+        NumThmPreprocessor {
+            label_lint: Some(LabelLint {
+                separator: ":".to_string(),
+                aliases: HashMap::from([("thm".to_string(), vec!["theorem".to_string()])]),
+                strict,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn label_prefix_matching() {
+        let pre = label_lint_pre(true);
+        let input = String::from("{{thm}}{thm:main}\n{{thm}}{theorem:aux}\n{{lem}}{lem:aux}");
+        assert!(pre.lint_label_prefixes(&input, &PATH).is_empty());
+    }
+
+    #[test]
+    fn label_prefix_mismatching() {
+        let pre = label_lint_pre(false);
+        let input = String::from(
+            "{{thm}}{thm:main}\n\nSome text.\n\n{{lem}}{thm:aux}[Auxiliary Lemma]\n\n\
+            ```\n{{lem}}{thm:code}\n```",
+        );
+        assert_eq!(
+            pre.lint_label_prefixes(&input, &PATH),
+            vec!["crypto/groups.md:5: Label `thm:aux' of a `lem' environment has prefix `thm'"]
+        );
+    }
+
+    #[test]
+    fn label_without_prefix() {
+        let input = String::from("{{thm}}{main}");
+        assert!(label_lint_pre(false)
+            .lint_label_prefixes(&input, &PATH)
+            .is_empty());
+        assert_eq!(
+            label_lint_pre(true).lint_label_prefixes(&input, &PATH),
+            vec!["crypto/groups.md:1: Label `main' of a `thm' environment has no prefix"]
+        );
+    }
+
+    #[test]
+    fn parenthesized_counter() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let env = Env {
+            counter_format: Some("({n})".to_string()),
+            ..Env::new("prop", "Proposition", "**")
+        };
+        let input =
+            String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{ref: prop:lagrange}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &env, &mut HashMap::new(), &mut refs);
+        let output = pre.find_and_replace_refs(&output, &PATH, &refs);
+        let expected = String::from(
+            "<a id=\"prop:lagrange\"></a>\n\
+            **Proposition (1.2.1) (Lagrange Theorem).** \
+            [Proposition (1.2.1)](#prop:lagrange)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parenthesized_counter_before_name() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let env = Env {
+            template: Some("{num} {name}".to_string()),
+            counter_format: Some("({n})".to_string()),
+            ..Env::new("thm", "Theorem", "**")
+        };
+        let input = String::from(r"{{thm}}{thm:main} {{ref: thm:main}}");
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &env, &mut HashMap::new(), &mut refs);
+        let output = pre.find_and_replace_refs(&output, &PATH, &refs);
+        let expected = String::from(
+            "<a id=\"thm:main\"></a>\n\
+            **(1.2.1) Theorem.** \
+            [(1.2.1) Theorem](#thm:main)",
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn rename_label() {
+        let pre = NumThmPreprocessor::default();
+        let input = String::from(
+            "{{thm}}{thm:main}[Main] {{lem}}{thm:main2}\n\
+            See {{ref: thm:main}}, {{tref:thm:main}}, and {{ref: thm:main2}}.\n\
+            <!-- numthm:skip-start -->{{ref: thm:main}}<!-- numthm:skip-end -->",
+        );
+        let (output, count) = pre.rename_label(&input, "thm:main", "thm:central");
+        let expected = String::from(
+            "{{thm}}{thm:central}[Main] {{lem}}{thm:main2}\n\
+            See {{ref: thm:central}}, {{tref:thm:central}}, and {{ref: thm:main2}}.\n\
+            <!-- numthm:skip-start -->{{ref: thm:main}}<!-- numthm:skip-end -->",
+        );
+        assert_eq!(output, expected);
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn rename_label_code_and_lists() {
+        let pre = NumThmPreprocessor::default();
+        for (input, expected, count) in [
+            // code regions and escaped directives are left untouched
+            (
+                "```\n{{ref: thm:main}}\n{{thm}}{thm:main}\n```\n`{{ref: thm:main}}` \\{{ref: thm:main}}",
+                "```\n{{ref: thm:main}}\n{{thm}}{thm:main}\n```\n`{{ref: thm:main}}` \\{{ref: thm:main}}",
+                0,
+            ),
+            // all occurrences in lists of labels and ranges are renamed
+            (
+                "{{ref: thm:main, thm:other}} {{ref:thm:other,thm:main}} {{ref: thm:main -- thm:main2}}",
+                "{{ref: thm:central, thm:other}} {{ref:thm:other,thm:central}} {{ref: thm:central -- thm:main2}}",
+                3,
+            ),
+            // similar labels are not renamed
+            (
+                "{{ref: thm:main2, thm:mai}} {{thm}}{thm:main2}",
+                "{{ref: thm:main2, thm:mai}} {{thm}}{thm:main2}",
+                0,
+            ),
+        ] {
+            let (output, n) = pre.rename_label(input, "thm:main", "thm:central");
+            assert_eq!(output, expected);
+            assert_eq!(n, count, "{input}");
+        }
+    }
+
+    #[test]
+    fn rename_label_in_book() {
+        let dir =
+            std::env::temp_dir().join(format!("mdbook-numthm-rename-label-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src/math")).unwrap();
+        std::fs::write(dir.join("book.toml"), "[preprocessor.numthm]\n").unwrap();
+        std::fs::write(
+            dir.join("src/SUMMARY.md"),
+            "# Summary\n\n- [Groups](math/groups.md)\n- [Signatures](signatures.md)\n- [Fields](fields.md)\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/math/groups.md"),
+            "{{prop}}{prop:lagrange}[Lagrange Theorem]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/signatures.md"),
+            "By {{ref: prop:lagrange}} and {{tref: prop:lagrange}}.\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("src/fields.md"), "{{thm}}{thm:frobenius}\n").unwrap();
+
+        // dry run
+        let changes =
+            NumThmPreprocessor::rename_label_in_book(&dir, "prop:lagrange", "prop:order", false)
+                .unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                (dir.join("src/math/groups.md"), 1),
+                (dir.join("src/signatures.md"), 2)
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/signatures.md")).unwrap(),
+            "By {{ref: prop:lagrange}} and {{tref: prop:lagrange}}.\n"
+        );
+
+        // the new label already exists
+        assert!(NumThmPreprocessor::rename_label_in_book(
+            &dir,
+            "prop:lagrange",
+            "thm:frobenius",
+            true
+        )
+        .is_err());
+
+        NumThmPreprocessor::rename_label_in_book(&dir, "prop:lagrange", "prop:order", true)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/math/groups.md")).unwrap(),
+            "{{prop}}{prop:order}[Lagrange Theorem]\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/signatures.md")).unwrap(),
+            "By {{ref: prop:order}} and {{tref: prop:order}}.\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_book() {
+        let dir = std::env::temp_dir().join(format!("mdbook-numthm-check-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("book.toml"),
+            "[preprocessor.numthm]\nlint_label_prefixes = true\nlabel_prefix_aliases = { thm = [\"theorem\"] }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/SUMMARY.md"),
+            "# Summary\n\n- [Groups](groups.md)\n- [Fields](fields.md)\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/groups.md"),
+            "{{thm}}{ thm:Main }[Main] {{lem}}{lem:a} {{lem}}{lem:A}\n\n{{def}}{def:group}\n",
+        )
+        .unwrap();
+        let fields = "See {{ ref :thm:main}} and {{ref: theorem:Main, def:group }}.\n\
+            {{ref: lem:b}} {{tref: LEM:a}} `{{ref: thm:main}}` {{thm}}{def:group}\n";
+        std::fs::write(dir.join("src/fields.md"), fields).unwrap();
+
+        let checked = NumThmPreprocessor::check_book(&dir, false).unwrap();
+        let problems: Vec<(String, usize, usize, String, bool)> = checked
+            .iter()
+            .flat_map(|file| {
+                file.problems.iter().map(|p| {
+                    let name = file.path.file_name().unwrap().to_string_lossy().to_string();
+                    (name, p.line, p.column, p.message.clone(), p.fixable)
+                })
+            })
+            .collect();
+        let problem = |name: &str, line, column, message: &str, fixable| {
+            (name.to_string(), line, column, message.to_string(), fixable)
+        };
+        assert_eq!(
+            problems,
+            vec![
+                problem(
+                    "groups.md",
+                    1,
+                    1,
+                    "Whitespace around label `thm:Main'",
+                    true
+                ),
+                problem("fields.md", 1, 5, "Spacing of `{{ ref :thm:main}}'", true),
+                problem(
+                    "fields.md",
+                    1,
+                    5,
+                    "Reference `thm:main' to label `thm:Main'",
+                    true
+                ),
+                problem(
+                    "fields.md",
+                    1,
+                    28,
+                    "Whitespace at the end of `{{ref: theorem:Main, def:group }}'",
+                    true
+                ),
+                problem(
+                    "fields.md",
+                    1,
+                    28,
+                    "Reference `theorem:Main' to label `thm:Main'",
+                    true
+                ),
+                problem("fields.md", 2, 1, "Unknown reference `lem:b'", false),
+                problem(
+                    "fields.md",
+                    2,
+                    16,
+                    "Ambiguous reference `LEM:a', which may refer to `lem:A', `lem:a'",
+                    false
+                ),
+                problem(
+                    "fields.md",
+                    2,
+                    52,
+                    "Label `def:group' already defined in groups.md",
+                    false
+                ),
+            ]
+            .into_iter()
+            .map(|(name, line, column, message, fixable)| {
+                let message = message.replace(
+                    "groups.md",
+                    &dir.join("src/groups.md").display().to_string(),
+                );
+                (name, line, column, message, fixable)
+            })
+            .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            checked[1].diff,
+            "@@ line 1 @@\n\
+            -See {{ ref :thm:main}} and {{ref: theorem:Main, def:group }}.\n\
+            +See {{ref: thm:Main}} and {{ref: thm:Main, def:group}}.\n"
+        );
+        // nothing is written without `write`
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/fields.md")).unwrap(),
+            fields
+        );
+
+        NumThmPreprocessor::check_book(&dir, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/groups.md")).unwrap(),
+            "{{thm}}{thm:Main}[Main] {{lem}}{lem:a} {{lem}}{lem:A}\n\n{{def}}{def:group}\n"
+        );
+        // unfixable problems are left untouched
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/fields.md")).unwrap(),
+            "See {{ref: thm:Main}} and {{ref: thm:Main, def:group}}.\n\
+            {{ref: lem:b}} {{tref: LEM:a}} `{{ref: thm:main}}` {{thm}}{def:group}\n"
+        );
+        let checked = NumThmPreprocessor::check_book(&dir, true).unwrap();
+        assert_eq!(checked.len(), 1);
+        assert_eq!(checked[0].problems.len(), 3);
+        assert_eq!(checked[0].fixed, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn label_positions() {
+        let pre = NumThmPreprocessor::default();
+        let mut refs = HashMap::new();
+        let mut definitions = Vec::new();
+        let mut references = Vec::new();
+        let input = String::from(
+            "# Groups\n\
+            \n\
+            {{prop}}{prop:lagrange}[Lagrange Theorem]\n\
+            \n\
+            By {{ref: prop:lagrange}} and {{ref: prop:cauchy}}, {{thm}}{thm:sylow}",
+        );
+        pre.find_label_positions(&input, &PATH, &mut definitions, &mut references);
+        let output =
+            pre.find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut HashMap::new(), &mut refs);
+        pre.find_and_replace_envs(&output, SECNUM, &PATH, &THM, &mut HashMap::new(), &mut refs);
+        let export = export_labels(&definitions, &references, &refs);
+        assert_eq!(
+            export,
+            serde_json::json!({
+                "definitions": [
+                    {
+                        "label": "prop:lagrange",
+                        "path": "crypto/groups.md",
+                        "offset": 10,
+                        "line": 3,
+                        "column": 1,
+                        "key": "prop",
+                        "num_name": "Proposition 1.2.1",
+                        "title": "Lagrange Theorem",
+                    },
+                    {
+                        "label": "thm:sylow",
+                        "path": "crypto/groups.md",
+                        "offset": 105,
+                        "line": 5,
+                        "column": 53,
+                        "key": "thm",
+                        "num_name": "Theorem 1.2.1",
+                        "title": null,
+                    },
+                ],
+                "references": [
+                    {
+                        "label": "prop:lagrange",
+                        "path": "crypto/groups.md",
+                        "offset": 56,
+                        "line": 5,
+                        "column": 4,
+                        "resolved": true,
+                    },
+                    {
+                        "label": "prop:cauchy",
+                        "path": "crypto/groups.md",
+                        "offset": 83,
+                        "line": 5,
+                        "column": 31,
+                        "resolved": false,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn inheriting_environments() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              { key = "claim", name = "Claim", inherits = "fact" },
+              { key = "fact", name = "Fact", inherits = "rem", counter_format = "({n})" },
+              { key = "cor", name = "Corollary", inherits = "thm" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{claim}} {{fact}} {{cor}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["*Claim (1).* *Fact (1).* **Corollary 1.**"]
+        );
+    }
+
+    #[test]
+    fn cjk_presets() {
+        let ctx = test_ctx(
+            r#"
+            [book]
+            language = "zh-CN"
+            [preprocessor.numthm]
+            custom_environments = [{ key = "dingli", name = "定理" }]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{dingli}}{a}[拉格朗日]\n\n见{{ref: a}}。")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["<a id=\"a\"></a>\n<strong>定理 1 (拉格朗日)。</strong>\n\n见[定理 1](#a)。"]
+        );
+
+        let ctx = test_ctx(
+            r#"
+            [book]
+            language = "ja"
+            [preprocessor.numthm]
+            custom_environments = [{ key = "teiri", name = "定理", emph = "*" }]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{teiri}}{a}\n\n{{ref: a}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["<a id=\"a\"></a>\n<em>定理1。</em>\n\n[定理1](#a)"]
+        );
+    }
+
+    #[test]
+    fn cjk_overrides() {
+        let ctx = test_ctx(
+            r#"
+            [book]
+            language = "zh"
+            [preprocessor.numthm]
+            name_separator = "\u3000"
+            header_punct = "："
+            html_emphasis = false
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{thm}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(contents(&book), vec!["**Theorem\u{3000}1：**"]);
+    }
+
+    #[test]
+    fn trailing_period() {
+        let run = |config: &str| {
+            let ctx = test_ctx(&format!("[preprocessor.numthm]\n{config}"));
+            let pre = NumThmPreprocessor::new(&ctx);
+            let book = test_book(&[("intro.md", "{{thm}} {{thm}}[Lagrange]")]);
+            contents(&pre.run(&ctx, book).unwrap())
+        };
+        assert_eq!(
+            run("trailing_period = true"),
+            vec!["**Theorem 1.** **Theorem 2 (Lagrange).**"]
+        );
+        assert_eq!(
+            run("trailing_period = false"),
+            vec!["**Theorem 1** **Theorem 2 (Lagrange)**"]
+        );
+        assert_eq!(
+            run("trailing_period = false\nheader_punct = \":\""),
+            vec!["**Theorem 1:** **Theorem 2 (Lagrange):**"]
+        );
+    }
+
+    #[test]
+    fn proof_detection() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            detect_proofs = true
+            proof_lead_words = ["Proof", "Démonstration"]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "intro.md",
+                "{{thm}}{thm:main}[Main Theorem] Groups are nice.\n\n\
+                Proof. Obvious.\n\n\
+                Proof of the main theorem. See above.\nIndeed.\n\n\
+                Démonstration du Theorem 1. Voir plus haut. ∎\n\n\
+                ```\nProof. Not a proof.\n```\n\n\
+                > Proof. Not a proof either.\n\n\
+                The Proof. Not a proof.\n\n\
+                Proof of Lemma 3.2. The end.",
+            ),
+            ("other.md", "Proof of Theorem 1. See intro.\n"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:main\"></a>\n**Theorem 1 (Main Theorem).** Groups are nice.\n\n\
+                *Proof.* Obvious. ∎\n\n\
+                *Proof of the [main theorem](#thm:main).* See above.\nIndeed. ∎\n\n\
+                *Démonstration du [Theorem 1](#thm:main).* Voir plus haut. ∎\n\n\
+                ```\nProof. Not a proof.\n```\n\n\
+                > Proof. Not a proof either.\n\n\
+                The Proof. Not a proof.\n\n\
+                *Proof of Lemma 3.2.* The end. ∎",
+                "*Proof of [Theorem 1](intro.md#thm:main).* See intro. ∎\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn emit_classes() {
+        let ctx = test_ctx("[preprocessor.numthm]\nemit_classes = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("groups.md", "{{thm}}{thm:a}[Lagrange] {{rem}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n\
+                <span class=\"numthm-env numthm-env-thm\">**Theorem 1 (Lagrange).**</span> \
+                <span class=\"numthm-env numthm-env-rem\">*Remark 1.*</span>"
+            ]
+        );
+        // existing books are not changed
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = pre
+            .run(&ctx, test_book(&[("groups.md", "{{thm}}")]))
+            .unwrap();
+        assert_eq!(contents(&book), vec!["**Theorem 1.**"]);
+    }
+
+    #[test]
+    fn margin_tags() {
+        let pre = NumThmPreprocessor {
+            margin_tags: true,
+            ..Default::default()
+        };
+        let output = pre.find_and_replace_envs(
+            "{{thm}}[Lagrange] The order of a subgroup divides the order of the group.",
+            SECNUM,
+            &PATH,
+            &THM,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        assert_eq!(
+            output,
+            "**<span class=\"numthm-margin\">Theorem 1.2.1</span> (Lagrange).** \
+            The order of a subgroup divides the order of the group."
+        );
+
+        // margin tags are only emitted for the HTML renderer
+        let pre = NumThmPreprocessor {
+            margin_tags: true,
+            html_renderer: false,
+            ..Default::default()
+        };
+        let output = pre.find_and_replace_envs(
+            "{{thm}}",
+            SECNUM,
+            &PATH,
+            &THM,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        assert_eq!(output, "**Theorem 1.2.1.**");
+    }
+
+    #[test]
+    fn clipboard_numbers() {
+        let pre = NumThmPreprocessor {
+            clipboard_numbers: true,
+            ..Default::default()
+        };
+        let output = pre.find_and_replace_envs(
+            "{{thm}}[Lagrange]",
+            SECNUM,
+            &PATH,
+            &THM,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        assert_eq!(
+            output,
+            "**<span class=\"numthm-number\" data-clipboard-text=\"Theorem 1.2.1\">Theorem 1.2.1</span> (Lagrange).**"
+        );
+
+        // the clipboard elements are only emitted for the HTML renderer
+        let ctx = test_ctx("[preprocessor.numthm]\nclipboard_numbers = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = pre
+            .run(&ctx, test_book(&[("intro.md", "{{thm}}")]))
+            .unwrap();
+        assert!(contents(&book)[0].contains("data-clipboard-text=\"Theorem 1\""));
+        let mut ctx = ctx;
+        ctx.renderer = "markdown".to_string();
+        let book = pre
+            .run(&ctx, test_book(&[("intro.md", "{{thm}}")]))
+            .unwrap();
+        assert_eq!(contents(&book), vec!["**Theorem 1.**"]);
+    }
+
+    #[test]
+    fn prefix_from_filename() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nprefix_from_filename = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("algebra/groups.md", "{{thm}}{thm:lagrange} {{thm}}"),
+            ("finite fields.md", "{{lem}} {{ref: thm:lagrange}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:lagrange\"></a>\n**Theorem groups.1.** **Theorem groups.2.**",
+                "**Lemma finite-fields.1.** [Theorem groups.1](algebra/groups.md#thm:lagrange)",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefix_overrides() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            reset = "never"
+
+            [preprocessor.numthm.prefix_overrides]
+            "problem-sets/week3.md" = "PS3."
+            "problem-sets/week4.md" = "PS4."
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("groups.md", "{{thm}} {{thm}}"),
+            ("problem-sets/week3.md", "{{thm}} {{thm}}{thm:b}"),
+            ("fields.md", "{{thm}} {{ref: thm:b}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.** **Theorem 1.2.**",
+                "**Theorem PS3.1.** <a id=\"thm:b\"></a>\n**Theorem PS3.2.**",
+                "**Theorem 3.3.** [Theorem PS3.2](problem-sets/week3.md#thm:b)",
+            ]
+        );
+    }
+
+    #[test]
+    fn quote_style() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [{ key = "qthm", name = "Theorem", style = "quote" }]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{qthm}}{thm:a}[Lagrange] The order of a subgroup\ndivides the order of the group.\n\n\
+            Some text.\n\n\
+            {{qthm}} Let $G$ be a group.\n\n\
+            - if $G$ is finite, then ...\n\
+            - otherwise ...\n\n\
+            > Quoted remark.\n\
+            {{qthm-end}}\n\n\
+            {{qthm}} Last statement.\n",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n\
+                > **Theorem 1 (Lagrange).** The order of a subgroup\n\
+                > divides the order of the group.\n\n\
+                Some text.\n\n\
+                > **Theorem 2.** Let $G$ be a group.\n\
+                >\n\
+                > - if $G$ is finite, then ...\n\
+                > - otherwise ...\n\
+                >\n\
+                > > Quoted remark.\n\n\
+                > **Theorem 3.** Last statement.\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn namespaced_directives() {
+        let ctx = test_ctx("[preprocessor.numthm]\nnamespace = \"numthm\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{numthm.thm}}{thm:a}{status=open} {{thm}}{thm:b} {{numthm.ref: thm:a}} {{ref: thm:b}} \
+            {{ref: other}}\n\n{{numthm.listof: thm}}\n\n{{numthm.open-problems}}\n\n{{open-problems}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** <span class=\"numthm-status numthm-status-open\">open</span> \
+                {{thm}}{thm:b} [Theorem 1](#thm:a) {{ref: thm:b}} {{ref: other}}\n\n\
+                - [Theorem 1](#thm:a)\n\n\
+                **Open** (1)\n\n- [Theorem 1](#thm:a)\n\n**Partially solved** (0)\n\n**Solved** (0)\n\n\
+                {{open-problems}}"
+            ]
+        );
+    }
+
+    #[test]
+    fn compiled_regexes() {
+        let envs =
+            "custom_environments = [{ key = \"cor\", name = \"Corollary\", inherits = \"thm\" }]";
+        for (config, namespace) in [
+            (format!("[preprocessor.numthm]\n{envs}"), ""),
+            (
+                format!("[preprocessor.numthm]\nnamespace = \"numthm\"\n{envs}"),
+                "numthm.",
+            ),
+        ] {
+            let pre = NumThmPreprocessor::new(&test_ctx(&config));
+            for env in &pre.envs {
+                assert_eq!(
+                    env.regex.as_str(),
+                    Env::compile_regex(&env.key, namespace, DirectiveSyntax::Brackets).as_str()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shared_counter() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            shared_counter = ["thm", "lem", "prop", "cor"]
+            custom_environments = [
+              { key = "cor", name = "Corollary" },
+              { key = "claim", name = "Claim", counter = "thm" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{thm}} {{lem}}{lem:a} {{def}} {{prop}} {{cor}} {{claim}} {{rem}} {{ref: lem:a}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.** <a id=\"lem:a\"></a>\n**Lemma 1.2.** **Definition 1.1.** \
+                **Proposition 1.3.** **Corollary 1.4.** **Claim 1.5.** *Remark 1.1.* [Lemma 1.2](#lem:a)"
+            ]
+        );
+
+        // counters are independent by default
+        let ctx = test_ctx("");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{thm}} {{lem}} {{prop}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["**Theorem 1.** **Lemma 1.** **Proposition 1.**"]
+        );
+    }
+
+    #[test]
+    fn shared_counter_all_environments() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            shared_counter = true
+            custom_environments = [
+              { key = "cor", name = "Corollary" },
+              { key = "claim", name = "Claim", counter = "thm" },
+              { key = "ex", name = "Exercise", counter = "exercises" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "intro.md",
+                "{{thm}} {{def}} {{lem}}{lem:a} {{prop}} {{ex}} {{cor}} {{claim}} {{hyp}} {{rem}} {{ref: lem:a}}",
+            ),
+            ("groups.md", "{{def}} {{hyp}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.** **Definition 1.2.** <a id=\"lem:a\"></a>\n**Lemma 1.3.** \
+                **Proposition 1.4.** **Exercise 1.1.** **Corollary 1.5.** **Claim 1.6.** **(H1).** \
+                *Remark 1.7.* [Lemma 1.3](#lem:a)",
+                "**Definition 2.1.** **(H2).**",
+            ]
+        );
+
+        // examples and exercises share the counter, but not equations
+        let book = test_book(&[(
+            "intro.md",
+            "{{thm}} {{eq}} {{example}} {{exer}} {{eq}} {{lem}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.** <span class=\"numthm-eq-tag\">(1.1)</span> *Example 1.2.* \
+                *Exercise 1.3.* <span class=\"numthm-eq-tag\">(1.2)</span> **Lemma 1.4.**"
+            ]
+        );
+    }
+
+    #[test]
+    fn document_order() {
+        // the order of the environments in the configuration does not matter
+        for envs in [r#"["b", "a"]"#, r#"["a", "b"]"#] {
+            let ctx = test_ctx(&format!(
+                r#"
+                [preprocessor.numthm]
+                shared_counter = {envs}
+                custom_environments = [
+                  {{ key = "b", name = "B" }},
+                  {{ key = "a", name = "A" }},
+                ]
+                "#
+            ));
+            let pre = NumThmPreprocessor::new(&ctx);
+            let book = test_book(&[(
+                "intro.md",
+                "{{a}} {{b}} {{b}} {{a}} {{b}}{b:x} {{ref: b:x}}",
+            )]);
+            let book = pre.run(&ctx, book).unwrap();
+            assert_eq!(
+                contents(&book),
+                vec![
+                    "**A 1.** **B 2.** **B 3.** **A 4.** <a id=\"b:x\"></a>\n**B 5.** [B 5](#b:x)"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn interleaved_environments() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            shared_counter = [["lem", "thm"], ["rem", "def"]]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{lem}} {{def}} {{thm}}{thm:a} {{rem}} {{prop}} {{lem}} {{def}} {{prop}} {{thm}} {{ref: thm:a}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Lemma 1.** **Definition 1.** <a id=\"thm:a\"></a>\n**Theorem 2.** *Remark 2.* \
+                **Proposition 1.** **Lemma 3.** **Definition 3.** **Proposition 2.** **Theorem 4.** \
+                [Theorem 2](#thm:a)"
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_counter_groups() {
+        let ctx = test_ctx(
+            "[preprocessor.numthm]\nshared_counter = [\"thm\", \"lem\", \"prop\", \"def\"]",
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{thm}} {{def}}{def:a} {{rem}} {{lem}} {{ref: def:a}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** <a id=\"def:a\"></a>\n**Definition 2.** *Remark 1.* **Lemma 3.** \
+                [Definition 2](#def:a)"
+            ]
+        );
+
+        let ctx = test_ctx(
+            "[preprocessor.numthm]\nshared_counter = [[\"thm\", \"lem\"], [\"def\", \"rem\"]]",
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{thm}} {{def}} {{rem}} {{lem}} {{prop}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["**Theorem 1.** **Definition 1.** *Remark 2.* **Lemma 2.** **Proposition 1.**"]
+        );
+    }
+
+    #[test]
+    fn ref_location_tooltip() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nref_location_tooltip = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[
+            ("groups.md", "{{thm}} {{thm}}{thm:a}"),
+            (
+                "fields.md",
+                "{{ref: thm:a}} {{jsref: thm:a}} {{ref: lem:b}}",
+            ),
+        ]);
+        let mut section = Chapter::new(
+            "Subgroups",
+            "{{lem}}{lem:b}".into(),
+            "subgroups.md",
+            Vec::new(),
+        );
+        section.number = Some(SectionNumber(vec![3, 2]));
+        book.push_item(section);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "[Theorem 1.2](groups.md#thm:a \"Chapter 1 > Theorem 1.2\") \
+            <a href=\"groups.md#thm:a\" class=\"numthm-jsref\" data-label=\"thm:a\" \
+            title=\"Chapter 1 > Theorem 1.2\"></a> \
+            [Lemma 3.2.1](subgroups.md#lem:b \"Chapter 3 > Section 3.2 > Lemma 3.2.1\")"
+        );
+        assert_eq!(location_tooltip(&[], "Theorem 1"), "Theorem 1");
+    }
+
+    #[test]
+    fn strict_unknown_refs() {
+        let chapters = [
+            (
+                "groups.md",
+                "{{thm}}{thm:a} {{ref: thm:a}} {{ref: thm:missing}}",
+            ),
+            ("fields.md", "{{ref: lem:other}} {{ref: thm:a}}"),
+        ];
+        let ctx = test_ctx("[preprocessor.numthm]\nstrict = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let err = pre.run(&ctx, test_book(&chapters)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "groups.md: Unknown reference `thm:missing'\n\
+            fields.md: Unknown reference `lem:other'"
+        );
+        // references are lenient by default
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert!(pre.run(&ctx, test_book(&chapters)).is_ok());
+    }
+
+    #[test]
+    fn strict_duplicate_labels() {
+        let chapters = [
+            ("groups.md", "{{thm}}{thm:a} {{lem}}{lem:b}"),
+            ("fields.md", "{{thm}}{thm:a} {{lem}}{lem:b} {{lem}}{lem:b}"),
+        ];
+        let ctx = test_ctx("[preprocessor.numthm]\nstrict = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let err = pre.run(&ctx, test_book(&chapters)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Label `lem:b' defined in groups.md and redefined in fields.md, fields.md\n\
+            Label `thm:a' defined in groups.md and redefined in fields.md"
+        );
+        // duplicate labels are only reported by default
+        let ctx = test_ctx("[preprocessor.numthm]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert!(pre.run(&ctx, test_book(&chapters)).is_ok());
+    }
+
+    #[test]
+    fn continuous_numbering() {
+        let ctx = test_ctx("[preprocessor.numthm]\nnumbering = \"continuous\"");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[
+            ("intro.md", "{{thm}} {{lem}} {{ref: thm:c}}"),
+            ("groups.md", "{{thm}} {{thm}}{thm:c}"),
+            ("fields.md", "{{thm}} {{lem}}"),
+        ]);
+        // a sub-chapter of the second chapter
+        let mut chapter = Chapter::new("cyclic", "{{thm}}".to_string(), "cyclic.md", Vec::new());
+        chapter.number = Some(SectionNumber(vec![2, 1]));
+        if let BookItem::Chapter(groups) = &mut book.sections[1] {
+            groups.sub_items.push(BookItem::Chapter(chapter));
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** **Lemma 1.** [Theorem 3](groups.md#thm:c)",
+                "**Theorem 2.** <a id=\"thm:c\"></a>\n**Theorem 3.**",
+                "**Theorem 4.**",
+                "**Theorem 5.** **Lemma 2.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn global_counter() {
+        let ctx = test_ctx("[preprocessor.numthm]\nglobal_counter = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("groups.md", "{{thm}} {{lem}} {{thm}}"),
+            ("fields.md", "{{thm}} {{lem}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** **Lemma 1.** **Theorem 2.**",
+                "**Theorem 3.** **Lemma 2.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_aliases() {
+        for (config, reset) in [
+            ("numbering = \"continuous\"", Reset::Never),
+            ("numbering = \"chapter\"\ncontinuous = true", Reset::Never),
+            ("global_counter = true", Reset::Never),
+            ("continuous = false", Reset::Chapter),
+            // `reset` takes precedence over its deprecated aliases
+            ("reset = \"part\"\nnumbering = \"continuous\"", Reset::Part),
+            ("global_counter = true\nreset = \"chapter\"", Reset::Chapter),
+        ] {
+            let ctx = test_ctx(&format!("[preprocessor.numthm]\n{config}"));
+            assert_eq!(NumThmPreprocessor::new(&ctx).reset, reset, "{config}");
+        }
+    }
+
+    #[test]
+    fn section_prefixes() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[("groups.md", "{{thm}} {{thm}}{thm:a}")]);
+        // chapter 3, section 3.2, and subsection 3.2.4
+        let mut subsection = Chapter::new("order", "{{thm}}".to_string(), "order.md", Vec::new());
+        subsection.number = Some(SectionNumber(vec![3, 2, 4]));
+        let mut section = Chapter::new(
+            "cyclic",
+            "{{lem}} {{ref: thm:a}}".to_string(),
+            "cyclic.md",
+            Vec::new(),
+        );
+        section.number = Some(SectionNumber(vec![3, 2]));
+        section.sub_items.push(BookItem::Chapter(subsection));
+        if let BookItem::Chapter(groups) = &mut book.sections[0] {
+            groups.number = Some(SectionNumber(vec![3]));
+            groups.sub_items.push(BookItem::Chapter(section));
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 3.1.** <a id=\"thm:a\"></a>\n**Theorem 3.2.**",
+                "**Lemma 3.2.1.** [Theorem 3.2](groups.md#thm:a)",
+                "**Theorem 3.2.4.1.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn heading_prefix() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = "headings"
+            heading_depth = 3
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "# Groups\n{{thm}}\n## Subgroups\n{{thm}} {{lem}}\n```\n## Not a heading\n```\n{{thm}}{thm:a}\n\
+                ### Cosets\n{{thm}}\n## Quotients\n#### Too deep\n{{thm}} {{hyp}}\n`code` ## Not a heading\n{{thm}}",
+            ),
+            ("fields.md", "## Extensions\n{{thm}} {{hyp}} {{ref: thm:a}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "# Groups\n**Theorem 1.1.**\n## Subgroups\n**Theorem 1.1.1.** **Lemma 1.1.1.**\n\
+                ```\n## Not a heading\n```\n<a id=\"thm:a\"></a>\n**Theorem 1.1.2.**\n\
+                ### Cosets\n**Theorem 1.1.1.1.**\n## Quotients\n#### Too deep\n**Theorem 1.2.1.** **(H1).**\n\
+                `code` ## Not a heading\n**Theorem 1.2.2.**",
+                "## Extensions\n**Theorem 2.1.1.** **(H2).** [Theorem 1.1.2](groups.md#thm:a)",
+            ]
+        );
+        assert_eq!(
+            find_headings("## A\n  ### B\n#### C\n##D", 3),
+            vec![(0, 2), (5, 3)]
+        );
+    }
+
+    #[test]
+    fn unnumbered_chapters() {
+        let run = |config: &str| {
+            let ctx = test_ctx(&format!("[preprocessor.numthm]\nprefix = true\n{config}"));
+            let pre = NumThmPreprocessor::new(&ctx);
+            let mut book = Book::new();
+            for (path, content) in [
+                ("preface.md", "{{thm}}{thm:a} {{lem}}"),
+                ("groups.md", "{{thm}} {{ref: thm:a}}"),
+                ("notes.md", "{{thm}}"),
+            ] {
+                book.push_item(Chapter::new(path, content.to_string(), path, Vec::new()));
+            }
+            if let BookItem::Chapter(chapter) = &mut book.sections[1] {
+                chapter.number = Some(SectionNumber(vec![1]));
+            }
+            contents(&pre.run(&ctx, book).unwrap())
+        };
+        assert_eq!(
+            run(""),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** **Lemma 1.**",
+                "**Theorem 1.1.** [Theorem 1](preface.md#thm:a)",
+                "**Theorem 1.**",
+            ]
+        );
+        assert_eq!(
+            run("unnumbered_chapters = \"stem\""),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem preface.1.** **Lemma preface.1.**",
+                "**Theorem 1.1.** [Theorem preface.1](preface.md#thm:a)",
+                "**Theorem notes.1.**",
+            ]
+        );
+        assert_eq!(
+            run("unnumbered_prefix = \"P.\""),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem P.1.** **Lemma P.1.**",
+                "**Theorem 1.1.** [Theorem P.1](preface.md#thm:a)",
+                "**Theorem P.1.**",
+            ]
+        );
+        assert_eq!(
+            run("unnumbered_chapters = \"unnumbered\""),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem.** **Lemma.**",
+                "**Theorem 1.1.** [Theorem](preface.md#thm:a)",
+                "**Theorem.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn truncate_section_numbers() {
+        let sn = SectionNumber(vec![1, 2, 3]);
+        assert_eq!(truncate_section_number(&sn, 1, "."), "1.");
+        assert_eq!(truncate_section_number(&sn, 2, "."), "1.2.");
+        assert_eq!(truncate_section_number(&sn, 3, "-"), "1-2-3-");
+        assert_eq!(truncate_section_number(&sn, usize::MAX, "."), "1.2.3.");
+        assert_eq!(truncate_section_number(&SectionNumber(vec![]), 2, "."), "");
+    }
+
+    #[test]
+    fn prefix_depth_two() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nprefix_depth = 2");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[("groups.md", "{{thm}}")]);
+        // sections 1.1, 1.1.1, and 1.2 of the first chapter
+        let mut subsection = Chapter::new("order", "{{thm}}".to_string(), "order.md", Vec::new());
+        subsection.number = Some(SectionNumber(vec![1, 1, 1]));
+        let mut section = Chapter::new("cyclic", "{{thm}}".to_string(), "cyclic.md", Vec::new());
+        section.number = Some(SectionNumber(vec![1, 1]));
+        section.sub_items.push(BookItem::Chapter(subsection));
+        let mut other = Chapter::new("free", "{{thm}}".to_string(), "free.md", Vec::new());
+        other.number = Some(SectionNumber(vec![1, 2]));
+        if let BookItem::Chapter(groups) = &mut book.sections[0] {
+            groups.sub_items.push(BookItem::Chapter(section));
+            groups.sub_items.push(BookItem::Chapter(other));
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.**",
+                "**Theorem 1.1.1.**",
+                "**Theorem 1.1.2.**",
+                "**Theorem 1.2.1.**",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefix_depth() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nprefix_depth = 1");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[
+            ("intro.md", "{{thm}}"),
+            ("groups.md", "{{thm}} {{lem}} {{ref: thm:d}}"),
+            ("fields.md", "{{thm}}"),
+        ]);
+        // sub-chapters 2.1 and 2.1.1 of the second chapter
+        let mut subsubchapter = Chapter::new(
+            "order",
+            "{{thm}}{thm:d}".to_string(),
+            "order.md",
+            Vec::new(),
+        );
+        subsubchapter.number = Some(SectionNumber(vec![2, 1, 1]));
+        let mut subchapter = Chapter::new(
+            "cyclic",
+            "{{thm}} {{lem}}".to_string(),
+            "cyclic.md",
+            Vec::new(),
+        );
+        subchapter.number = Some(SectionNumber(vec![2, 1]));
+        subchapter.sub_items.push(BookItem::Chapter(subsubchapter));
+        if let BookItem::Chapter(groups) = &mut book.sections[1] {
+            groups.sub_items.push(BookItem::Chapter(subchapter));
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.**",
+                "**Theorem 2.1.** **Lemma 2.1.** [Theorem 2.3](order.md#thm:d)",
+                "**Theorem 2.2.** **Lemma 2.2.**",
+                "<a id=\"thm:d\"></a>\n**Theorem 2.3.**",
+                "**Theorem 3.1.**",
+            ]
+        );
     }
-}
-
-impl Default for NumThmPreprocessor {
-    fn default() -> Self {
-        let thm: Env = Env {
-            key: "thm".to_string(),
-            name: "Theorem".to_string(),
-            emph: "**".to_string(),
-        };
 
-        let lem: Env = Env {
-            key: "lem".to_string(),
-            name: "Lemma".to_string(),
-            emph: "**".to_string(),
-        };
+    #[test]
+    fn continuous_numbering_with_prefix() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\ncontinuous = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("intro.md", "{{thm}} {{thm}}"),
+            ("groups.md", "{{thm}}{thm:c} {{lem}}"),
+            ("fields.md", "{{ref: thm:c}} {{thm}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.** **Theorem 1.2.**",
+                "<a id=\"thm:c\"></a>\n**Theorem 2.3.** **Lemma 2.1.**",
+                "[Theorem 2.3](groups.md#thm:c) **Theorem 3.4.**",
+            ]
+        );
+    }
 
-        let prop: Env = Env {
-            key: "prop".to_string(),
-            name: "Proposition".to_string(),
-            emph: "**".to_string(),
-        };
+    #[test]
+    fn global_unique_anchors() {
+        let chapters = [
+            ("intro.md", "{{thm}}{thm:a} {{thm}}{thm:b}"),
+            ("groups.md", "{{lem}}{thm:a} {{lem}}{lem:c}"),
+            ("fields.md", "{{thm}}{thm:a}"),
+        ];
+        // duplicate labels are only warned about by default
+        let ctx = test_ctx("");
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert!(pre.run(&ctx, test_book(&chapters)).is_ok());
 
-        let def: Env = Env {
-            key: "def".to_string(),
-            name: "Definition".to_string(),
-            emph: "**".to_string(),
-        };
+        let ctx = test_ctx("[preprocessor.numthm]\nglobal_unique_anchors = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let error = pre.run(&ctx, test_book(&chapters)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Label `thm:a' defined in several chapters: intro.md, groups.md, fields.md"
+        );
+        // definitions in code regions are not labels
+        let book = test_book(&[
+            ("intro.md", "{{thm}}{thm:a}"),
+            (
+                "groups.md",
+                "{{lem}}{lem:a} `{{thm}}{thm:a}`\n```\n{{thm}}{thm:a}\n```",
+            ),
+        ]);
+        assert!(pre.run(&ctx, book).is_ok());
+    }
 
-        let rem: Env = Env {
-            key: "rem".to_string(),
-            name: "Remark".to_string(),
-            emph: "*".to_string(),
-        };
+    #[test]
+    fn emphasis_attribute() {
+        let ctx = test_ctx("");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "intro.md",
+            "{{rem}}{rem:a}[Important]{emph=**} {{rem}} {{thm}}{emph=\"\"}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["<a id=\"rem:a\"></a>\n**Remark 1 (Important).** *Remark 2.* Theorem 1."]
+        );
+    }
 
-        Self {
-            envs: vec![thm, lem, prop, def, rem],
-            with_prefix: false,
+    #[test]
+    fn reset_policies() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            reset = "part"
+            custom_environments = [
+              { key = "ex", name = "Exercise", reset = "never" },
+              { key = "note", name = "Note", reset = "chapter" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[("preface.md", "{{thm}} {{ex}} {{note}}")]);
+        book.push_item(BookItem::PartTitle("Part I".to_string()));
+        let mut chapters = test_book(&[
+            ("groups.md", "{{thm}}{thm:a} {{ex}} {{note}}"),
+            ("fields.md", "{{thm}} {{ex}} {{note}} {{ref: thm:c}}"),
+        ])
+        .sections;
+        // a sub-chapter of the first chapter of the first part
+        let mut chapter = Chapter::new(
+            "cyclic",
+            "{{thm}} {{note}}".to_string(),
+            "cyclic.md",
+            Vec::new(),
+        );
+        chapter.number = Some(SectionNumber(vec![1, 1]));
+        if let BookItem::Chapter(groups) = &mut chapters[0] {
+            groups.sub_items.push(BookItem::Chapter(chapter));
         }
+        book.sections.append(&mut chapters);
+        book.push_item(BookItem::PartTitle("Part II".to_string()));
+        book.sections
+            .append(&mut test_book(&[("rings.md", "{{thm}}{thm:c} {{ex}} {{note}}")]).sections);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.** **Exercise 1.** **Note 1.**",
+                "<a id=\"thm:a\"></a>\n**Theorem 1.** **Exercise 2.** **Note 1.**",
+                "**Theorem 2.** **Note 1.**",
+                "**Theorem 3.** **Exercise 3.** **Note 1.** [Theorem 1](rings.md#thm:c)",
+                "<a id=\"thm:c\"></a>\n**Theorem 1.** **Exercise 4.** **Note 1.**",
+            ]
+        );
     }
-}
 
-impl Preprocessor for NumThmPreprocessor {
-    fn name(&self) -> &str {
-        NAME
+    #[test]
+    fn appendices() {
+        let ctx =
+            test_ctx("[preprocessor.numthm]\nprefix = true\nappendix_paths = [\"appendix/*.md\"]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[
+            ("intro.md", "{{thm}}"),
+            ("groups.md", "{{thm}} {{hyp}} {{ref: thm:a}} `{{appendix}}`"),
+            ("appendix/proofs.md", "{{thm}}{thm:a}"),
+            ("notations.md", "{{appendix}}{{thm}} {{lem}} {{hyp}}"),
+            ("index.md", "{{thm}}"),
+        ]);
+        // a sub-chapter of the first appendix
+        let mut chapter = Chapter::new(
+            "sets",
+            "{{thm}}".to_string(),
+            "appendix/sets.md",
+            Vec::new(),
+        );
+        chapter.number = Some(SectionNumber(vec![3, 1]));
+        if let BookItem::Chapter(proofs) = &mut book.sections[2] {
+            proofs.sub_items.push(BookItem::Chapter(chapter));
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.**",
+                "**Theorem 2.1.** **(H1).** [Theorem A.1](appendix/proofs.md#thm:a) `{{appendix}}`",
+                "<a id=\"thm:a\"></a>\n**Theorem A.1.**",
+                "**Theorem A.1.1.**",
+                "**Theorem B.1.** **Lemma B.1.** **(H2).**",
+                "**Theorem C.1.**",
+            ]
+        );
+        assert_eq!(appendix_letter(0), "A");
+        assert_eq!(appendix_letter(25), "Z");
+        assert_eq!(appendix_letter(26), "AA");
     }
 
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        // a hashmap mapping labels to `LabelInfo` structs
-        let mut refs: HashMap<String, LabelInfo> = HashMap::new();
-
-        book.for_each_mut(|item: &mut BookItem| {
-            if let BookItem::Chapter(chapter) = item {
-                if !chapter.is_draft_chapter() {
-                    // one can safely unwrap chapter.path which must be Some(...)
-                    let prefix = if self.with_prefix {
-                        match &chapter.number {
-                            Some(sn) => sn.to_string(),
-                            None => String::new(),
-                        }
-                    } else {
-                        String::new()
-                    };
-                    let path = chapter.path.as_ref().unwrap();
-                    for env in &self.envs {
-                        chapter.content =
-                            find_and_replace_envs(&chapter.content, &prefix, path, env, &mut refs);
-                    }
-                }
-            }
-        });
-
-        book.for_each_mut(|item: &mut BookItem| {
-            if let BookItem::Chapter(chapter) = item {
-                if !chapter.is_draft_chapter() {
-                    // one can safely unwrap chapter.path which must be Some(...)
-                    let path = chapter.path.as_ref().unwrap();
-                    chapter.content = find_and_replace_refs(&chapter.content, path, &refs);
-                }
-            }
-        });
+    #[test]
+    fn only_paths() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nonly_paths = [\"main/**\"]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("main/intro.md", "{{thm}}{thm:a} {{thm}}{thm:b}"),
+            (
+                "notes.md",
+                "{{thm}}{thm:c}[Aside] {{thm}}{thm:d} {{ref: thm:a}}",
+            ),
+            (
+                "main/end.md",
+                "{{sref: thm:c}} {{tref: thm:c}}, {{ref: thm:c--thm:d}} {{ref: thm:a, thm:c}} \
+                 {{listof: thm}}\n\n{{proof}}[of thm:c] Done.{{proof-end}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        let output = contents(&book);
+        // the excluded chapter is left as is, but its environments are numbered
+        assert_eq!(
+            output[1],
+            "{{thm}}{thm:c}[Aside] {{thm}}{thm:d} {{ref: thm:a}}"
+        );
+        // references to them are rendered as plain text
+        assert!(output[2]
+            .starts_with("2.1 Aside, Theorems 2.1–2.2 Theorems [1.1](intro.md#thm:a) and 2.1 "));
+        assert!(output[2].contains("- Theorem 2.1 (Aside)\n- Theorem 2.2\n"));
+        assert!(output[2].contains("<summary>Proof of Theorem 2.1.</summary>"));
+        assert!(!output[2].contains("notes.md"));
 
-        Ok(book)
+        // with citations, the list of references keeps the anchors of the citations
+        let ctx =
+            test_ctx("[preprocessor.numthm]\nonly_paths = [\"main/**\"]\ncitation_mode = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("notes.md", "{{thm}}{thm:a}"),
+            ("main/end.md", "{{ref: thm:a}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "[\\[1\\]](#numthm-cite-1)\n\n**References**\n\n- <a id=\"numthm-cite-1\"></a>\\[1\\] Theorem 1\n"
+        );
     }
-}
-
-/// Finds all patterns `{{key}}{mylabel}[mytitle]` where `key` is the key field of `env` (e.g. `thm`)
-/// and replaces them with a header (including the title if a title `mytitle` is provided)
-/// and potentially an anchor if a label `mylabel` is provided;
-/// if a label is provided, it updates the hashmap `refs` with an entry (label, LabelInfo)
-/// allowing to format links to the theorem.
-fn find_and_replace_envs(
-    s: &str,
-    prefix: &str,
-    path: &Path,
-    env: &Env,
-    refs: &mut HashMap<String, LabelInfo>,
-) -> String {
-    let mut ctr = 0;
 
-    let key = &env.key;
-    let name = &env.name;
-    let emph = &env.emph;
+    #[test]
+    fn active_environments() {
+        let ctx = test_ctx("[preprocessor.numthm]\nactive_environments = [\"thm\", \"eq\"]");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("intro.md", "{{lem}}{lem:a} {{thm}} {{ref: lem:a}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec!["{{lem}}{lem:a} **Theorem 1.** **[??]**"]
+        );
+    }
 
-    let mut pattern = r"\{\{".to_string();
-    pattern.push_str(key);
-    pattern.push_str(r"\}\}(\{(?P<label>.*?)\})?(\[(?P<title>.*?)\])?");
-    // see https://regex101.com/ for an explanation of the regex "\{\{key\}\}\{(?P<label>.*?)\}(\[(?P<title>.*?)\])?"
-    // matches {{key}}{label}[title] where {label} and [title] are optional
-    let re: Regex = Regex::new(pattern.as_str()).unwrap();
-
-    re.replace_all(s, |caps: &regex::Captures| {
-        ctr += 1;
-        let anchor = match caps.name("label") {
-            Some(match_label) => {
-                // if a label is given, we must update the hashmap
-                let label = match_label.as_str().to_string();
-                if refs.contains_key(&label) {
-                    // if the same label has already been used we emit a warning and don't update the hashmap
-                    warn!("{name} {prefix}{ctr}: Label `{label}' already used");
-                } else {
-                    refs.insert(
-                        label.clone(),
-                        LabelInfo {
-                            num_name: format!("{name} {prefix}{ctr}"),
-                            path: path.to_path_buf(),
-                            title: caps.name("title").map(|t| t.as_str().to_string()),
-                        },
-                    );
-                }
-                format!("<a name=\"{label}\"></a>\n")
-            }
-            None => String::new(),
-        };
-        let header = match caps.name("title") {
-            Some(match_title) => {
-                let title = match_title.as_str().to_string();
-                format!("{emph}{name} {prefix}{ctr} ({title}).{emph}")
-            }
-            None => {
-                format!("{emph}{name} {prefix}{ctr}.{emph}")
-            }
-        };
-        format!("{anchor}{header}")
-    })
-    .to_string()
-}
+    #[test]
+    fn start() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              ["thm", "Theorem", "**", 17],
+              { key = "ax", name = "Axiom", start = 3 },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("groups.md", "{{thm}} {{thm}} {{ax}} {{lem}}"),
+            ("fields.md", "{{thm}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 17.** **Theorem 18.** **Axiom 3.** **Lemma 1.**",
+                "**Theorem 17.**",
+            ]
+        );
+    }
 
-/// Finds and replaces all patterns {{ref: label}} where label is an existing key in hashmap `refs`
-/// with a link towards the relevant theorem.
-fn find_and_replace_refs(
-    s: &str,
-    chap_path: &PathBuf,
-    refs: &HashMap<String, LabelInfo>,
-) -> String {
-    // see https://regex101.com/ for an explanation of the regex
-    let re: Regex = Regex::new(r"\{\{(?P<reftype>ref:|tref:)\s*(?P<label>.*?)\}\}").unwrap();
-
-    re.replace_all(s, |caps: &regex::Captures| {
-        let label = caps.name("label").unwrap().as_str().to_string();
-        if refs.contains_key(&label) {
-            let text = match caps.name("reftype").unwrap().as_str() {
-                "ref:" => &refs.get(&label).unwrap().num_name,
-                _ => {
-                    // this must be tref if there is a match
-                    match &refs.get(&label).unwrap().title {
-                        Some(t) => t,
-                        // fallback to the numbered name in case the label does not have an associated title
-                        None => &refs.get(&label).unwrap().num_name,
-                    }
-                }
-            };
-            let path_to_ref = &refs.get(&label).unwrap().path;
-            let rel_path = compute_rel_path(chap_path, path_to_ref);
-            format!("[{text}]({rel_path}#{label})")
-        } else {
-            warn!("Unknown reference: {}", label);
-            "**[??]**".to_string()
-        }
-    })
-    .to_string()
-}
+    #[test]
+    fn number_offset() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            number_offset = 10
+            custom_environments = [{ key = "ax", name = "Axiom", start = 3 }]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("groups.md", "{{thm}}{thm:a} {{thm}} {{ax}} {{lem}}"),
+            ("fields.md", "{{thm}} {{ref: thm:a}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**Theorem 11.** **Theorem 12.** **Axiom 13.** **Lemma 11.**",
+                "**Theorem 11.** [Theorem 11](groups.md#thm:a)",
+            ]
+        );
+    }
 
-/// Computes the relative path from the folder containing `chap_path` to the file `path_to_ref`.
-fn compute_rel_path(chap_path: &PathBuf, path_to_ref: &PathBuf) -> String {
-    if chap_path == path_to_ref {
-        return "".to_string();
+    #[test]
+    fn header_templates() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            custom_environments = [
+              { key = "thm", name = "Theorem", header_template = "{num}. {name}" },
+              { key = "ax", name = "Axiom", header_template = "{name} {num} — {title}" },
+              { key = "q", name = "Question", header_template = "{name} {num" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "groups.md",
+            "{{thm}}{thm:a} {{thm}}[Lagrange] {{ax}}[Choice] {{q}} {{ref: thm:a}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"thm:a\"></a>\n**1.1. Theorem** **1.2. Theorem** **Axiom 1.1 — Choice** \
+                **Question 1.1.** [Theorem 1.1](#thm:a)"
+            ]
+        );
     }
-    let mut local_chap_path = chap_path.clone();
-    local_chap_path.pop();
-    format!(
-        "{}",
-        diff_paths(path_to_ref, &local_chap_path).unwrap().display()
-    )
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use lazy_static::lazy_static;
+    #[test]
+    fn show_total() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              { key = "ex", name = "Exercise", show_total = true },
+              { key = "q", name = "Question", show_total = true, reset = "never" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("groups.md", "{{ex}} {{ex}}{ex:b}[Cosets] {{ex}} {{q}}"),
+            ("fields.md", "{{ex}} {{q}} {{ex}} {{ref: ex:b}}"),
+            ("rings.md", "{{q}} {{thm}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Exercise 1 of 3.** <a id=\"ex:b\"></a>\n**Exercise 2 of 3 (Cosets).** **Exercise 3 of 3.** \
+                **Question 1 of 3.**",
+                "**Exercise 1 of 2.** **Question 2 of 3.** **Exercise 2 of 2.** [Exercise 2](groups.md#ex:b)",
+                "**Question 3 of 3.** **Theorem 1.**",
+            ]
+        );
+    }
 
-    const SECNUM: &str = "1.2.";
+    #[test]
+    fn numbered_within() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            custom_environments = [
+              { key = "corr", name = "Corollary", within = "thm" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{corr}} {{thm}} {{corr}}{corr:a} {{corr}} {{lem}} {{thm}} {{corr}} {{tref: corr:a}}",
+            ),
+            ("fields.md", "{{corr}} {{ref: corr:a}}"),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Corollary 1.1.** **Theorem 1.1.** <a id=\"corr:a\"></a>\n**Corollary 1.1.1.** \
+                **Corollary 1.1.2.** **Lemma 1.1.** **Theorem 1.2.** **Corollary 1.2.1.** [Corollary 1.1.1](#corr:a)",
+                "**Corollary 2.1.** [Corollary 1.1.1](groups.md#corr:a)",
+            ]
+        );
+    }
 
-    lazy_static! {
-        static ref THM: Env = Env {
-            key: "thm".to_string(),
-            name: "Theorem".to_string(),
-            emph: "**".to_string(),
-        };
-        static ref PROP: Env = Env {
-            key: "prop".to_string(),
-            name: "Proposition".to_string(),
-            emph: "**".to_string(),
-        };
-        static ref PATH: PathBuf = "crypto/groups.md".into();
+    #[test]
+    fn capture_line() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              { key = "note", name = "Note", emph = "*", capture_line = true },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "groups.md",
+            "{{note}} The order divides the order.\nNot a note.\n\n{{note}}{note:b}[Cosets] Cosets.\r\n\
+            {{note}}\n{{rem}} Not captured.\n",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "*Note 1. The order divides the order.*\nNot a note.\n\n<a id=\"note:b\"></a>\n\
+                *Note 2 (Cosets). Cosets.*\n*Note 3.*\n*Remark 1.* Not captured.\n",
+            ]
+        );
     }
 
     #[test]
-    fn wo_label_wo_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut refs);
-        let expected = String::from("**Proposition 1.2.1.**");
-        assert_eq!(output, expected);
-        assert!(refs.is_empty());
+    fn pad_width() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            custom_environments = [
+              { key = "ex", name = "Exercise", pad_width = 3 },
+              { key = "q", name = "Question", pad_width = 3, numbering_style = "roman-lower" },
+            ]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[("groups.md", "{{ex}}{ex:a} {{thm}} {{q}} {{ref: ex:a}}")]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "<a id=\"ex:a\"></a>\n**Exercise 1.001.** **Theorem 1.1.** **Question 1.i.** \
+                [Exercise 1.001](#ex:a)"
+            ]
+        );
     }
 
     #[test]
-    fn with_label_wo_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}{prop:lagrange}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1.**",
+    fn numbering_styles() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            numbering_style = "roman-lower"
+            custom_environments = [
+              ["conj", "Conjecture", "*", "alpha-upper"],
+              { key = "ax", name = "Axiom", numbering_style = "roman-upper" },
+            ]
+            "#,
         );
-        assert_eq!(output, expected);
-        assert_eq!(refs.len(), 1);
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[(
+            "groups.md",
+            "{{thm}} {{thm}}{thm:b} {{conj}} {{conj}} {{ax}} {{ax}} {{ref: thm:b}}",
+        )]);
+        let book = pre.run(&ctx, book).unwrap();
         assert_eq!(
-            *refs.get("prop:lagrange").unwrap(),
-            LabelInfo {
-                num_name: "Proposition 1.2.1".to_string(),
-                path: "crypto/groups.md".into(),
-                title: None,
-            }
-        )
+            contents(&book),
+            vec![
+                "**Theorem i.** <a id=\"thm:b\"></a>\n**Theorem ii.** *Conjecture A.* *Conjecture B.* \
+                **Axiom I.** **Axiom II.** [Theorem ii](#thm:b)"
+            ]
+        );
+        assert_eq!(format_counter(4, NumberingStyle::RomanLower), "iv");
+        assert_eq!(format_counter(9, NumberingStyle::RomanLower), "ix");
+        assert_eq!(format_counter(40, NumberingStyle::RomanUpper), "XL");
+        assert_eq!(format_counter(1994, NumberingStyle::RomanUpper), "MCMXCIV");
+        assert_eq!(format_counter(27, NumberingStyle::AlphaLower), "aa");
+        assert_eq!(format_counter(40, NumberingStyle::Arabic), "40");
     }
 
     #[test]
-    fn wo_label_with_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}[Lagrange Theorem]");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut refs);
-        let expected = String::from("**Proposition 1.2.1 (Lagrange Theorem).**");
-        assert_eq!(output, expected);
-        assert!(refs.is_empty());
+    fn appendix_part() {
+        let ctx = test_ctx(
+            "[preprocessor.numthm]\nprefix = true\nappendix_part = \"Appendices\"\nappendix_start = \"extra/\"",
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = test_book(&[
+            ("groups.md", "{{thm}} {{ref: thm:a}}"),
+            ("extra/notes.md", "{{thm}}"),
+        ]);
+        book.push_item(BookItem::PartTitle("Appendices".to_string()));
+        let mut appendices = test_book(&[
+            ("proofs.md", "{{thm}} {{lem}}"),
+            ("tables.md", "{{thm}}{thm:a}"),
+        ])
+        .sections;
+        for (i, item) in appendices.iter_mut().enumerate() {
+            if let BookItem::Chapter(appendix) = item {
+                appendix.number = Some(SectionNumber(vec![i as u32 + 3]));
+            }
+        }
+        // a sub-chapter of the first appendix
+        let mut chapter = Chapter::new("sets", "{{thm}}".to_string(), "sets.md", Vec::new());
+        chapter.number = Some(SectionNumber(vec![3, 1]));
+        if let BookItem::Chapter(proofs) = &mut appendices[0] {
+            proofs.sub_items.push(BookItem::Chapter(chapter));
+        }
+        book.sections.append(&mut appendices);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.1.** [Theorem C.1](tables.md#thm:a)",
+                "**Theorem A.1.**",
+                "**Theorem B.1.** **Lemma B.1.**",
+                "**Theorem B.1.1.**",
+                "<a id=\"thm:a\"></a>\n**Theorem C.1.**",
+            ]
+        );
     }
 
     #[test]
-    fn with_label_with_title() {
-        let mut refs = HashMap::new();
-        let input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1 (Lagrange Theorem).**",
+    fn appendix_prefix() {
+        let ctx = test_ctx("[preprocessor.numthm]\nprefix = true\nappendix_prefix = true");
+        let pre = NumThmPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        for (path, content, number) in [
+            ("preface.md", "{{thm}}", None),
+            ("groups.md", "{{thm}} {{thm}} {{ref: thm:a}}", Some(1)),
+            ("fields.md", "{{thm}}", Some(2)),
+            ("proofs.md", "{{thm}} {{lem}}", None),
+            ("tables.md", "{{thm}}{thm:a}", None),
+        ] {
+            let mut chapter = Chapter::new(path, content.to_string(), path, Vec::new());
+            chapter.number = number.map(|n| SectionNumber(vec![n]));
+            book.push_item(chapter);
+        }
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book),
+            vec![
+                "**Theorem 1.**",
+                "**Theorem 1.1.** **Theorem 1.2.** [Theorem B.1](tables.md#thm:a)",
+                "**Theorem 2.1.**",
+                "**Theorem A.1.** **Lemma A.1.**",
+                "<a id=\"thm:a\"></a>\n**Theorem B.1.**",
+            ]
         );
-        assert_eq!(output, expected);
     }
 
     #[test]
-    fn double_label() {
-        let mut refs = HashMap::new();
-        let input = String::from(
-            r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{thm}}{prop:lagrange}[Another Lagrange Theorem]",
+    fn short_numbering_styles() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            custom_environments = [
+              ["conj", "Conjecture", "*", "Alpha", 26],
+              { key = "aside", name = "Aside", numbering_style = "roman" },
+            ]
+            "#,
         );
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut refs);
-        let output = find_and_replace_envs(&output, SECNUM, &PATH, &THM, &mut refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1 (Lagrange Theorem).** \
-            <a name=\"prop:lagrange\"></a>\n\
-            **Theorem 1.2.1 (Another Lagrange Theorem).**",
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            ("intro.md", ""),
+            (
+                "groups.md",
+                "{{conj}} {{conj}}{conj:a} {{aside}} {{thm}} {{ref: conj:a}}",
+            ),
+        ]);
+        let book = pre.run(&ctx, book).unwrap();
+        assert_eq!(
+            contents(&book)[1],
+            "*Conjecture 2.Z.* <a id=\"conj:a\"></a>\n*Conjecture 2.AA.* **Aside 2.i.** **Theorem 2.1.** \
+            [Conjecture 2.AA](#conj:a)"
         );
-        assert_eq!(output, expected);
-        assert_eq!(refs.len(), 1);
     }
 
     #[test]
-    fn label_and_ref_in_same_file() {
-        let mut refs = HashMap::new();
-        let input =
-            String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem] {{ref: prop:lagrange}}");
-        let output = find_and_replace_envs(&input, SECNUM, &PATH, &PROP, &mut refs);
-        let output = find_and_replace_refs(&output, &PATH, &refs);
-        let expected = String::from(
-            "<a name=\"prop:lagrange\"></a>\n\
-            **Proposition 1.2.1 (Lagrange Theorem).** \
-            [Proposition 1.2.1](#prop:lagrange)",
+    fn inheritance_cycle() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            custom_environments = [
+              { key = "a", name = "A", inherits = "b" },
+              { key = "b", name = "B", inherits = "a" },
+              { key = "c", name = "C", inherits = "c" },
+              { key = "d", name = "D", inherits = "unknown" },
+            ]
+            "#,
         );
-        assert_eq!(output, expected);
+        let pre = NumThmPreprocessor::new(&ctx);
+        assert_eq!(pre.envs.len(), NumThmPreprocessor::default().envs.len());
     }
 
     #[test]
-    fn label_and_ref_in_different_files() {
-        let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/groups.md".into();
-        let ref_file: PathBuf = "crypto/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let ref_input = String::from(r"{{ref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &PROP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Proposition 1.2.1](../math/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+    fn idempotency() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            custom_environments = [["conj", "Conjecture", "*"]]
+            "#,
+        );
+        let pre = NumThmPreprocessor::new(&ctx);
+        let book = test_book(&[
+            (
+                "groups.md",
+                "{{thm}}{thm:lagrange}[Lagrange Theorem]\n\
+                {{conj}}{conj:open}{status=open} {{lem}}\n\
+                <!-- numthm:skip-start -->{{ref: thm:lagrange}}<!-- numthm:skip-end -->\n\
+                See {{ref: thm:lagrange}} and {{ref: thm:unknown}}.",
+            ),
+            (
+                "proofs.md",
+                "{{proof}}\nObvious.\n{{proof-end}}\n\n$$\na = b\n$$\n{{eq}}{eq:a} {{eqref: eq:a}}",
+            ),
+            ("index.md", "{{listof}}\n\n{{open-problems}}"),
+            ("syntax.md", r"Write \{{thm}} to get {{thm}}."),
+            ("plain.md", "No directive here."),
+        ]);
+        let once = pre.run(&ctx, book.clone()).unwrap();
+        // only the output containing unescaped directives is marked
+        let marked: Vec<bool> = contents(&once)
+            .iter()
+            .map(|content| content.starts_with(PROCESSED_START))
+            .collect();
+        assert_eq!(marked, vec![false, false, false, true, false]);
+        let twice = pre.run(&ctx, once.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&twice).unwrap(),
+            serde_json::to_string(&once).unwrap()
+        );
+        // markers are not emitted for renderers displaying comments
+        let mut ctx = ctx;
+        ctx.renderer = "markdown".to_string();
+        let once = pre.run(&ctx, book).unwrap();
+        assert!(contents(&once)
+            .iter()
+            .all(|content| !content.contains(PROCESSED_START)));
     }
 
     #[test]
-    fn label_and_ref_in_different_files_2() {
+    fn escaped_braces() {
+        let pre = NumThmPreprocessor::default();
         let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/algebra/groups.md".into();
-        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let ref_input = String::from(r"{{ref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &PROP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+        let label_file: PathBuf = "math/groups.md".into();
+        let label_input = String::from(r"{{prop}}{prop:\{a\}}[Order of $\{e\}$]");
+        let label_output = pre.find_and_replace_envs(
+            &label_input,
+            SECNUM,
+            &label_file,
+            &PROP,
+            &mut HashMap::new(),
+            &mut refs,
+        );
+        assert_eq!(
+            label_output,
+            "<a id=\"prop:%7Ba%7D\"></a>\n**Proposition 1.2.1 (Order of ${e}$).**"
+        );
+        assert_eq!(refs["prop:{a}"].title.as_deref(), Some("Order of ${e}$"));
+        let ref_input = String::from(r"{{ref: prop:\{a\}}} and {{tref: prop:\{a\}}}");
+        let ref_output = pre.find_and_replace_refs(&ref_input, &label_file, &refs);
+        assert_eq!(
+            ref_output,
+            "[Proposition 1.2.1](#prop:%7Ba%7D) and [Order of ${e}$](#prop:%7Ba%7D)"
+        );
     }
 
     #[test]
-    fn title_ref() {
-        let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/algebra/groups.md".into();
-        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}[Lagrange Theorem]");
-        let ref_input = String::from(r"{{tref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &PROP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Lagrange Theorem](../../algebra/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+    fn rename_escaped_label() {
+        let pre = NumThmPreprocessor::default();
+        let input = r"{{prop}}{a} {{ref: a}}";
+        let (output, count) = pre.rename_label(input, "a", "b{1}");
+        assert_eq!(output, r"{{prop}}{b\{1\}} {{ref: b\{1\}}}");
+        assert_eq!(count, 2);
+        assert!(pre.defines_label(&output, "b{1}"));
+        assert!(!pre.defines_label("`{{prop}}{c}` \\{{prop}}{c}\n```\n{{prop}}{c}\n```", "c"));
     }
 
     #[test]
-    fn title_ref_without_title() {
-        let mut refs = HashMap::new();
-        let label_file: PathBuf = "math/algebra/groups.md".into();
-        let ref_file: PathBuf = "math/crypto//signatures/bls_signatures.md".into();
-        let label_input = String::from(r"{{prop}}{prop:lagrange}");
-        let ref_input = String::from(r"{{tref: prop:lagrange}}");
-        let _label_output =
-            find_and_replace_envs(&label_input, SECNUM, &label_file, &PROP, &mut refs);
-        let ref_output = find_and_replace_refs(&ref_input, &ref_file, &refs);
-        let expected = String::from("[Proposition 1.2.1](../../algebra/groups.md#prop:lagrange)");
-        assert_eq!(ref_output, expected);
+    fn dump_config() {
+        let ctx = test_ctx(
+            r#"
+            [preprocessor.numthm]
+            prefix = true
+            custom_environments = [
+              ["conj", "Conjecture", "*"],
+              { key = "cor", name = "Corollary", inherits = "thm", counter_format = "({n})" },
+            ]
+            "#,
+        );
+        let dump = NumThmPreprocessor::new(&ctx).dump_config();
+        assert!(dump.contains("prefix = true\n"));
+        assert!(dump.contains(
+            "[[environments]]\n\
+            emph = \"**\"\n\
+            key = \"thm\"\n\
+            name = \"Theorem\"\n"
+        ));
+        assert!(dump.contains(
+            "[[environments]]\n\
+            emph = \"*\"\n\
+            key = \"conj\"\n\
+            name = \"Conjecture\"\n"
+        ));
+        assert!(dump.contains(
+            "[[environments]]\n\
+            counter_format = \"({n})\"\n\
+            emph = \"**\"\n\
+            key = \"cor\"\n\
+            name = \"Corollary\"\n"
+        ));
     }
 }
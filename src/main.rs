@@ -1,9 +1,11 @@
-use clap::{crate_version, Arg, ArgMatches, Command};
+use clap::{crate_version, Arg, ArgAction, ArgMatches, Command};
 use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_numthm::NumThmPreprocessor;
+use mdbook::Config;
+use mdbook_numthm::{install_assets, NumThmPreprocessor};
 use semver::{Version, VersionReq};
 use std::io;
+use std::path::PathBuf;
 
 /// Parse CLI options.
 pub fn make_app() -> Command {
@@ -15,6 +17,64 @@ pub fn make_app() -> Command {
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("install")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Install the assets highlighting targeted environments"),
+        )
+        .subcommand(
+            Command::new("rename")
+                .arg(Arg::new("old").required(true).help("Label to rename"))
+                .arg(Arg::new("new").required(true).help("New label"))
+                .arg(
+                    Arg::new("book-dir")
+                        .long("book-dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::new("write")
+                        .long("write")
+                        .action(ArgAction::SetTrue)
+                        .help("Write the changes (otherwise, only print them)"),
+                )
+                .about("Rename a label in its definition and all references"),
+        )
+        .subcommand(
+            Command::new("check")
+                .arg(
+                    Arg::new("book-dir")
+                        .long("book-dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(Arg::new("fix").long("fix").action(ArgAction::SetTrue).help(
+                    "Fix mechanical problems (only print the changes unless --write is given)",
+                ))
+                .arg(
+                    Arg::new("write")
+                        .long("write")
+                        .action(ArgAction::SetTrue)
+                        .requires("fix")
+                        .help("Write the fixes"),
+                )
+                .about("Check labels and references, failing if problems are found"),
+        )
+        .subcommand(
+            Command::new("dump-config")
+                .arg(
+                    Arg::new("book-dir")
+                        .long("book-dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Print the resolved configuration, including all environments"),
+        )
 }
 
 fn handle_preprocessing() -> Result<()> {
@@ -46,6 +106,7 @@ fn handle_supports(sub_args: &ArgMatches) -> Result<()> {
         .get_one::<String>("renderer")
         .expect("Required argument");
 
+    // mdbook only calls `supports` when the `renderers` option is not set, so the default configuration applies
     let pre = NumThmPreprocessor::default();
 
     let supported = pre.supports_renderer(renderer);
@@ -61,6 +122,116 @@ fn handle_supports(sub_args: &ArgMatches) -> Result<()> {
     }
 }
 
+fn handle_install(sub_args: &ArgMatches) -> Result<()> {
+    let dir: PathBuf = sub_args
+        .get_one::<String>("dir")
+        .expect("Default value")
+        .into();
+
+    for file_path in install_assets(&dir)? {
+        eprintln!("Wrote {}", file_path.display());
+    }
+    eprintln!(
+        "Make sure your book.toml contains:\n\n\
+         [output.html]\n\
+         additional-css = [\"numthm.css\"]\n\
+         additional-js = [\"numthm.js\"]"
+    );
+
+    Ok(())
+}
+
+fn handle_rename(sub_args: &ArgMatches) -> Result<()> {
+    let old = sub_args
+        .get_one::<String>("old")
+        .expect("Required argument");
+    let new = sub_args
+        .get_one::<String>("new")
+        .expect("Required argument");
+    let book_dir: PathBuf = sub_args
+        .get_one::<String>("book-dir")
+        .expect("Default value")
+        .into();
+    let write = sub_args.get_flag("write");
+
+    let changes = NumThmPreprocessor::rename_label_in_book(&book_dir, old, new, write)?;
+    if changes.is_empty() {
+        eprintln!("No occurrence of label `{old}' found");
+    }
+    for (file_path, count) in changes {
+        eprintln!("{}: {count} occurrence(s)", file_path.display());
+    }
+    if !write {
+        eprintln!("Dry run: use --write to apply the changes");
+    }
+
+    Ok(())
+}
+
+fn handle_check(sub_args: &ArgMatches) -> Result<()> {
+    let book_dir: PathBuf = sub_args
+        .get_one::<String>("book-dir")
+        .expect("Default value")
+        .into();
+    let fix = sub_args.get_flag("fix");
+    let write = sub_args.get_flag("write");
+
+    let checked = NumThmPreprocessor::check_book(&book_dir, write)?;
+    let mut remaining = 0;
+    for file in &checked {
+        let fixed = file.problems.iter().filter(|p| fix && p.fixable).count();
+        for problem in &file.problems {
+            let status = match (fix && problem.fixable, write) {
+                (true, true) => "fixed: ",
+                (true, false) => "to be fixed: ",
+                (false, _) => "",
+            };
+            eprintln!(
+                "{}:{}:{}: {status}{}",
+                file.path.display(),
+                problem.line,
+                problem.column,
+                problem.message
+            );
+        }
+        if fixed > 0 {
+            eprintln!("{}: {fixed} fix(es)", file.path.display());
+            if !write {
+                eprint!(
+                    "--- {}\n+++ {}\n{}",
+                    file.path.display(),
+                    file.path.display(),
+                    file.diff
+                );
+            }
+        }
+        remaining += file.problems.len() - if write { fixed } else { 0 };
+    }
+    if fix && !write && checked.iter().any(|file| file.fixed.is_some()) {
+        eprintln!("Dry run: use --write to apply the fixes");
+    }
+
+    match remaining {
+        0 => Ok(()),
+        _ if !fix && checked.iter().any(|file| file.fixed.is_some()) => Err(Error::msg(format!(
+            "{remaining} problem(s) found, use --fix to fix the mechanical ones"
+        ))),
+        _ => Err(Error::msg(format!("{remaining} problem(s) found"))),
+    }
+}
+
+fn handle_dump_config(sub_args: &ArgMatches) -> Result<()> {
+    let book_dir: PathBuf = sub_args
+        .get_one::<String>("book-dir")
+        .expect("Default value")
+        .into();
+
+    let config = Config::from_disk(book_dir.join("book.toml"))?;
+    print!("{}", NumThmPreprocessor::from_config(&config).dump_config());
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
     ::std::env::set_var("RUST_LOG", "warn");
     env_logger::init();
@@ -69,6 +240,18 @@ fn main() -> Result<()> {
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         // handle cmdline supports
         handle_supports(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("install") {
+        // handle cmdline install
+        handle_install(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("rename") {
+        // handle cmdline rename
+        handle_rename(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("check") {
+        // handle cmdline check
+        handle_check(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("dump-config") {
+        // handle cmdline dump-config
+        handle_dump_config(sub_args)
     } else {
         // handle preprocessing
         handle_preprocessing()